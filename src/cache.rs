@@ -0,0 +1,332 @@
+use super::environments::{self, Snapshot};
+use super::recipe::Decision;
+use super::record;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Where detection results are cached, relative to record::RECORD_DIR
+pub const CACHE_DIR: &str = "cache";
+/// Changes whenever the format of cache files does, so old files are ignored
+const FORMAT: &str = "snap-out-cache-3";
+/// Written after everything else, so files cut short are noticed
+const END: &str = "end";
+
+/// Returns the directory cache files are kept in, under XDG_RUNTIME_DIR
+pub fn get_dir(runtime_dir: Option<&OsStr>) -> Option<PathBuf> {
+    let runtime_dir = Path::new(runtime_dir?);
+    if !runtime_dir.is_absolute() {
+        return None;
+    }
+    Some(runtime_dir.join(record::RECORD_DIR).join(CACHE_DIR))
+}
+
+/// A cache file is named after the boundary process, so it is only used while that process lives
+fn get_file_name(pid: i32, start_time: u64) -> String {
+    format!("{}-{}", pid, start_time)
+}
+
+/// Returns the PID and start time from the name of a cache file
+fn parse_file_name(name: &OsStr) -> Option<(i32, u64)> {
+    let (pid, start_time) = name.to_str()?.split_once('-')?;
+    Some((pid.parse().ok()?, start_time.parse().ok()?))
+}
+
+/// What a cache was written for, which has to match for it to be used
+pub fn get_header(sandbox: &str, instance: &str, nesting: environments::Nesting) -> [String; 4] {
+    [
+        String::from(FORMAT),
+        String::from(sandbox),
+        String::from(instance),
+        format!("{:?}", nesting),
+    ]
+}
+
+/// Returns when a rules file was last changed, or "-" if there is none
+fn get_stamp(path: &Path) -> String {
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok());
+    match modified {
+        Some(modified) => modified.as_nanos().to_string(),
+        None => String::from("-"),
+    }
+}
+
+/// Fields are separated by NUL bytes, which can't appear in any of them
+fn push_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend_from_slice(field);
+    bytes.push(0);
+}
+
+/// Values are written with a + in front, and missing ones as -
+fn push_value(bytes: &mut Vec<u8>, value: &Option<OsString>) {
+    match value {
+        Some(value) => push_field(bytes, &[b"+", value.as_bytes()].concat()),
+        None => push_field(bytes, b"-"),
+    }
+}
+
+/// Writes the decisions after the header, along with the rules files they were made with and when
+///   those were last changed
+fn serialize(header: &[String], rules: &[PathBuf], decisions: &[Decision]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for field in header {
+        push_field(&mut bytes, field.as_bytes());
+    }
+    push_field(&mut bytes, rules.len().to_string().as_bytes());
+    for path in rules {
+        push_field(&mut bytes, path.as_os_str().as_bytes());
+        push_field(&mut bytes, get_stamp(path).as_bytes());
+    }
+    push_field(&mut bytes, decisions.len().to_string().as_bytes());
+    for decision in decisions {
+        push_field(&mut bytes, decision.name.as_bytes());
+        push_value(&mut bytes, &decision.from);
+        push_value(&mut bytes, &decision.to);
+    }
+    push_field(&mut bytes, END.as_bytes());
+    bytes
+}
+
+/// Reads the fields written by push_field()
+struct Fields<'a> {
+    fields: std::slice::Split<'a, u8, fn(&u8) -> bool>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        let is_separator: fn(&u8) -> bool = |b| *b == 0;
+        Fields {
+            fields: bytes.split(is_separator),
+        }
+    }
+
+    fn next_bytes(&mut self) -> Option<&'a [u8]> {
+        self.fields.next()
+    }
+
+    fn next_str(&mut self) -> Option<&'a str> {
+        std::str::from_utf8(self.next_bytes()?).ok()
+    }
+
+    fn next_number<T: std::str::FromStr>(&mut self) -> Option<T> {
+        self.next_str()?.parse().ok()
+    }
+
+    fn next_value(&mut self) -> Option<Option<OsString>> {
+        match self.next_bytes()? {
+            b"-" => Some(None),
+            value => Some(Some(
+                OsStr::from_bytes(value.strip_prefix(b"+")?).to_owned(),
+            )),
+        }
+    }
+}
+
+/// Reads what serialize() wrote, returning None if it is invalid, the header doesn't match or a
+///   rules file has changed since
+fn deserialize(bytes: &[u8], header: &[String]) -> Option<Vec<Decision>> {
+    let mut fields = Fields::new(bytes);
+    for expected in header {
+        if fields.next_str()? != expected {
+            return None;
+        }
+    }
+    for _ in 0..fields.next_number::<usize>()? {
+        let path = Path::new(OsStr::from_bytes(fields.next_bytes()?));
+        if fields.next_str()? != get_stamp(path) {
+            return None;
+        }
+    }
+    let mut decisions = Vec::new();
+    for _ in 0..fields.next_number::<usize>()? {
+        let name = OsStr::from_bytes(fields.next_bytes()?).to_owned();
+        let from = fields.next_value()?;
+        let to = fields.next_value()?;
+        decisions.push(Decision { name, from, to });
+    }
+    if fields.next_str()? != END {
+        return None;
+    }
+    Some(decisions)
+}
+
+/// Looks for the decisions cached for the launch the current process belongs to, before any
+///   ancestor is looked at
+/// A cache is only used while its boundary process is still running, and if that process is in
+///   the same cgroup as the current one, which snapd makes specific to each launch (see
+///   record::find()). The decisions still have to be applied with recipe::apply(), as they were
+///   made for the environment of the process that wrote them.
+pub fn find(
+    dir: &Path,
+    header: &[String],
+    myself: &Snapshot,
+) -> Result<Option<Vec<Decision>>, Box<dyn Error>> {
+    if myself.cgroups.is_empty() {
+        return Ok(None);
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        let (pid, start_time) = match parse_file_name(&name) {
+            Some(key) => key,
+            None => continue,
+        };
+        let cgroups: Vec<String> = match procfs::Process::new(pid) {
+            Ok(process) if process.stat.starttime as u64 == start_time => match process.cgroups() {
+                Ok(cgroups) => cgroups.into_iter().map(|c| c.pathname).collect(),
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        // The boundary may have exited and its PID been reused while it was being read
+        if !record::is_running(pid, start_time) || cgroups != myself.cgroups {
+            continue;
+        }
+        if let Some(decisions) =
+            record::read_private(&dir.join(&name))?.and_then(|bytes| deserialize(&bytes, header))
+        {
+            return Ok(Some(decisions));
+        }
+    }
+    Ok(None)
+}
+
+/// Caches the decisions of a run under its boundary process, and removes the caches of boundaries
+///   that have exited
+/// Only called when find() found nothing, so each launch is written once unless its environment
+///   or rules change
+pub fn save(
+    dir: &Path,
+    boundary: &Snapshot,
+    header: &[String],
+    rules: &[PathBuf],
+    decisions: &[Decision],
+) -> Result<(), Box<dyn Error>> {
+    record::write_private(
+        &dir.join(get_file_name(boundary.pid, boundary.start_time)),
+        &serialize(header, rules, decisions),
+    )?;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some((pid, start_time)) = parse_file_name(&name) {
+            if !record::is_running(pid, start_time) {
+                let _ = std::fs::remove_file(dir.join(&name));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fallback;
+    use super::super::process::ProcfsProcess;
+    use super::*;
+    use std::collections::HashMap;
+
+    fn decision(name: &str, from: Option<&str>, to: Option<&str>) -> Decision {
+        Decision {
+            name: OsString::from(name),
+            from: from.map(OsString::from),
+            to: to.map(OsString::from),
+        }
+    }
+
+    fn mock_decisions() -> Vec<Decision> {
+        vec![
+            decision("PATH", Some("/snap/foo/1/bin:/usr/bin"), Some("/usr/bin")),
+            decision("GTK_PATH", Some("/snap/foo/1/gtk"), None),
+            decision("EDITOR", None, Some("")),
+            decision("MULTILINE", Some("a\nb"), Some("-")),
+        ]
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("snap-out-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn builds_dir() {
+        assert_eq!(
+            get_dir(Some(OsStr::new("/run/user/1000"))),
+            Some(PathBuf::from("/run/user/1000/snap-out/cache"))
+        );
+        assert_eq!(get_dir(Some(OsStr::new("relative"))), None);
+        assert_eq!(get_dir(None), None);
+        assert_eq!(
+            parse_file_name(OsStr::new(&get_file_name(1234, 5678))),
+            Some((1234, 5678))
+        );
+        assert_eq!(parse_file_name(OsStr::new(".1234.tmp")), None);
+    }
+
+    #[test]
+    fn round_trips_decisions() {
+        let header = get_header("snap", "foo", environments::Nesting::All);
+        let bytes = serialize(&header, &[], &mock_decisions());
+        assert_eq!(deserialize(&bytes, &header), Some(mock_decisions()));
+        let other = get_header("snap", "foo", environments::Nesting::Innermost);
+        assert_eq!(deserialize(&bytes, &other), None);
+        let other = get_header("snap", "foo_beta", environments::Nesting::All);
+        assert_eq!(deserialize(&bytes, &other), None);
+        assert_eq!(deserialize(&bytes[..bytes.len() - 3], &header), None);
+    }
+
+    #[test]
+    fn ignores_cache_once_rules_change() {
+        let dir = temp_dir("cache-rules-test");
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let rules = vec![dir.join("rules.conf")];
+        let header = get_header("snap", "foo", environments::Nesting::All);
+        let bytes = serialize(&header, &rules, &mock_decisions());
+        assert_eq!(deserialize(&bytes, &header), Some(mock_decisions()));
+        std::fs::write(&rules[0], "PATH=keep\n").expect("Failed to write rules");
+        assert_eq!(deserialize(&bytes, &header), None);
+        let bytes = serialize(&header, &rules, &mock_decisions());
+        assert_eq!(deserialize(&bytes, &header), Some(mock_decisions()));
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn saves_and_finds_by_cgroup() {
+        let dir = temp_dir("cache-test");
+        let header = get_header("snap", "foo", environments::Nesting::All);
+        let myself =
+            Snapshot::from_process(&ProcfsProcess::myself().expect("Failed to read own process"));
+        assert!(find(&dir, &header, &myself)
+            .expect("Failed to find in missing dir")
+            .is_none());
+        let mut ended = Snapshot::from_source(fallback::Source::Recorded, HashMap::new());
+        ended.pid = myself.pid;
+        ended.start_time = u64::MAX;
+        save(&dir, &ended, &header, &[], &mock_decisions()).expect("Failed to save");
+        save(&dir, &myself, &header, &[], &mock_decisions()).expect("Failed to save");
+        // Saving removes caches of boundaries that have exited
+        assert!(!dir
+            .join(get_file_name(ended.pid, ended.start_time))
+            .exists());
+        assert_eq!(
+            find(&dir, &header, &myself).expect("Failed to find"),
+            Some(mock_decisions())
+        );
+        // Other instances and other launches (in other cgroups) don't use it
+        let other = get_header("snap", "bar", environments::Nesting::All);
+        assert!(find(&dir, &other, &myself)
+            .expect("Failed to find")
+            .is_none());
+        let mut elsewhere = Snapshot::from_source(fallback::Source::Recorded, HashMap::new());
+        elsewhere.cgroups = vec![String::from("/other.scope")];
+        assert!(find(&dir, &header, &elsewhere)
+            .expect("Failed to find")
+            .is_none());
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+}
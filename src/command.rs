@@ -1,19 +1,59 @@
+use super::detach;
+use super::spawn;
 use std::borrow::Borrow;
 use std::ffi::{OsStr, OsString};
+use std::os::unix::io::RawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::time::Duration;
 
 #[allow(dead_code)]
 pub const NO_ARGS: std::iter::Empty<OsString> = std::iter::empty();
 #[allow(dead_code)]
 pub const NO_VARS: std::iter::Empty<(OsString, Option<OsString>)> = std::iter::empty();
 
-/// Runs a command and returns it's exit code
-pub fn run(
+/// Exit codes follow the conventions of the shell (and of env, for our own failures)
+pub const EXIT_INTERNAL_ERROR: i32 = 125;
+pub const EXIT_NOT_EXECUTABLE: i32 = 126;
+pub const EXIT_NOT_FOUND: i32 = 127;
+/// Added to the signal number when the command is killed by a signal
+pub const EXIT_SIGNAL_BASE: i32 = 128;
+
+/// How long to wait for a command started in Mode::Session to exit
+pub const SESSION_WAIT: Duration = Duration::from_secs(2);
+
+/// How a command is run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    /// Replace this process with the command, so it keeps the same PID and gets signals directly
+    Exec,
+    /// Run the command as a child process, passing signals on to it, and wait for it to exit
+    Spawn,
+    /// Start the command outside of this process's session and return without waiting for it,
+    ///   optionally logging its output and writing its PID to a file
+    Detach {
+        log: Option<String>,
+        pid_file: Option<String>,
+    },
+    /// Start the command in a session of its own, cut off from this process's terminal like a
+    ///   detached command, and wait up to SESSION_WAIT for it to exit so that early failures are
+    ///   still passed on. If it is still running after that, it is left to run on its own.
+    Session,
+}
+
+/// Sets up a command with the given changes to the environment
+/// If argv0 is given, it replaces the name the command sees itself run as
+fn build(
     cmd: &str,
+    argv0: Option<&str>,
     args: impl IntoIterator<Item = impl AsRef<OsStr>>,
     envs: impl IntoIterator<Item = impl Borrow<(OsString, Option<OsString>)>>,
-) -> i32 {
+) -> std::process::Command {
     let mut command = std::process::Command::new(cmd);
     command.args(args);
+    if let Some(argv0) = argv0 {
+        command.arg0(argv0);
+    }
     for var in envs {
         let (key, val) = var.borrow();
         if let Some(val) = val {
@@ -22,27 +62,100 @@ pub fn run(
             command.env_remove(key);
         }
     }
-    // actually run the command
-    let status = command.status();
+    command
+}
+
+/// Returns the exit code for a command that could not be run
+fn get_error_exit_code(error: &std::io::Error) -> i32 {
+    match error.raw_os_error() {
+        Some(libc::ENOENT) | Some(libc::ENOTDIR) => EXIT_NOT_FOUND,
+        Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::ENOEXEC) | Some(libc::EISDIR)
+        | Some(libc::ETXTBSY) => EXIT_NOT_EXECUTABLE,
+        _ => EXIT_INTERNAL_ERROR,
+    }
+}
+
+/// Runs a command in the given mode and returns it's exit code
+/// In exec mode this only returns if the command could not be run
+pub fn run(
+    mode: &Mode,
+    cmd: &str,
+    argv0: Option<&str>,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    envs: impl IntoIterator<Item = impl Borrow<(OsString, Option<OsString>)>>,
+) -> i32 {
+    run_command(mode, build(cmd, argv0, args, envs), &[])
+}
+
+/// Like run(), but for a command that is already set up
+/// inherited_fds are file descriptors the command is given on purpose, which are kept open even
+///   when it is detached
+pub fn run_command(
+    mode: &Mode,
+    mut command: std::process::Command,
+    inherited_fds: &[RawFd],
+) -> i32 {
+    let cmd = command.get_program().to_string_lossy().into_owned();
+    let status = match mode {
+        Mode::Exec => {
+            let error = command.exec();
+            eprintln!(
+                "{}: Failed to execute {}: {}",
+                env!("CARGO_PKG_NAME"),
+                cmd,
+                error
+            );
+            return get_error_exit_code(&error);
+        }
+        Mode::Detach { log, pid_file } => {
+            let log = log.as_deref().map(Path::new);
+            let pid_file = pid_file.as_deref().map(Path::new);
+            return match detach::run(command, log, pid_file, inherited_fds) {
+                Ok(_) => 0,
+                Err(error) => {
+                    eprintln!(
+                        "{}: Failed to run {}: {}",
+                        env!("CARGO_PKG_NAME"),
+                        cmd,
+                        error
+                    );
+                    match error.downcast_ref::<std::io::Error>() {
+                        Some(error) => get_error_exit_code(error),
+                        None => EXIT_INTERNAL_ERROR,
+                    }
+                }
+            };
+        }
+        Mode::Session => match detach::start_in_session(command, inherited_fds, SESSION_WAIT) {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => return 0,
+            Err(error) => Err(error),
+        },
+        Mode::Spawn => spawn::supervise(command),
+    };
     match status {
         Ok(status) => {
             if let Some(exit_code) = status.code() {
-                return exit_code;
+                exit_code
+            } else if let Some(signal) = status.signal() {
+                spawn::mirror_signal(signal);
+                EXIT_SIGNAL_BASE + signal
             } else {
                 eprintln!(
                     "{}: child process terminated without an exit code",
                     env!("CARGO_PKG_NAME")
                 );
-                return 1;
+                EXIT_INTERNAL_ERROR
             }
         }
         Err(error) => {
             eprintln!(
-                "{}: Failed to run child process: {}",
+                "{}: Failed to run {}: {}",
                 env!("CARGO_PKG_NAME"),
+                cmd,
                 error
             );
-            return 1;
+            get_error_exit_code(&error)
         }
     }
 }
@@ -53,22 +166,40 @@ mod tests {
 
     #[test]
     fn running_true_returns_0() {
-        assert_eq!(run("true", NO_ARGS, NO_VARS,), 0);
+        assert_eq!(run(&Mode::Spawn, "true", None, NO_ARGS, NO_VARS,), 0);
     }
 
     #[test]
     fn running_false_returns_1() {
-        assert_eq!(run("false", NO_ARGS, NO_VARS,), 1);
+        assert_eq!(run(&Mode::Spawn, "false", None, NO_ARGS, NO_VARS,), 1);
     }
 
     #[test]
     fn evaluates_true_bash_exp() {
-        assert_eq!(run("bash", vec!["-c", "[ 3 -eq 3 ]"], NO_VARS,), 0);
+        assert_eq!(
+            run(
+                &Mode::Spawn,
+                "bash",
+                None,
+                vec!["-c", "[ 3 -eq 3 ]"],
+                NO_VARS,
+            ),
+            0
+        );
     }
 
     #[test]
     fn evaluates_false_bash_exp() {
-        assert_eq!(run("bash", vec!["-c", "[ 3 -eq 5 ]"], NO_VARS,), 1);
+        assert_eq!(
+            run(
+                &Mode::Spawn,
+                "bash",
+                None,
+                vec!["-c", "[ 3 -eq 5 ]"],
+                NO_VARS,
+            ),
+            1
+        );
     }
 
     #[test]
@@ -76,8 +207,48 @@ mod tests {
         let cmd = "bash";
         let args = vec!["-c", "[ -z $HOME ]"];
         let vars = vec![(OsString::from("HOME"), None)];
-        assert_eq!(run(&cmd, &args, NO_VARS), 1);
-        assert_eq!(run(&cmd, &args, vars), 0);
+        assert_eq!(run(&Mode::Spawn, cmd, None, &args, NO_VARS), 1);
+        assert_eq!(run(&Mode::Spawn, cmd, None, &args, vars), 0);
+    }
+
+    #[test]
+    fn can_override_argv0() {
+        let args = vec!["-c", "[ \"$0\" = custom ]"];
+        assert_eq!(run(&Mode::Spawn, "sh", None, &args, NO_VARS), 1);
+        assert_eq!(run(&Mode::Spawn, "sh", Some("custom"), &args, NO_VARS), 0);
+    }
+
+    #[test]
+    fn exec_returns_when_command_is_missing() {
+        assert_eq!(
+            run(
+                &Mode::Exec,
+                "snap-out-missing-command",
+                None,
+                NO_ARGS,
+                NO_VARS
+            ),
+            EXIT_NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn uses_shell_exit_codes_for_failures() {
+        let missing = "snap-out-missing-command";
+        assert_eq!(
+            run(&Mode::Spawn, missing, None, NO_ARGS, NO_VARS),
+            EXIT_NOT_FOUND
+        );
+        let directory = std::env::temp_dir();
+        let directory = directory.to_str().unwrap();
+        assert_eq!(
+            run(&Mode::Spawn, directory, None, NO_ARGS, NO_VARS),
+            EXIT_NOT_EXECUTABLE
+        );
+        assert_eq!(
+            run(&Mode::Exec, directory, None, NO_ARGS, NO_VARS),
+            EXIT_NOT_EXECUTABLE
+        );
     }
 
     #[test]
@@ -85,7 +256,7 @@ mod tests {
         let cmd = "bash";
         let args = vec!["-c", "[ -z $FOO ]"];
         let vars = vec![(OsString::from("FOO"), Some(OsString::from("BAR")))];
-        assert_eq!(run(&cmd, &args, NO_VARS), 0);
-        assert_eq!(run(&cmd, &args, vars), 1);
+        assert_eq!(run(&Mode::Spawn, cmd, None, &args, NO_VARS), 0);
+        assert_eq!(run(&Mode::Spawn, cmd, None, &args, vars), 1);
     }
 }
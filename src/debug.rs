@@ -7,13 +7,17 @@ pub const DEBUG_ENV_VAR: &str = "SNAP_OUT_DEBUG";
 pub const DEBUG_DUMP_PATH: &str = "/tmp/snap-out-debug.log";
 
 fn get_environment_info(manager: &mut manager::Manager) -> Result<String, Rc<dyn Error>> {
-    Ok(format!("{:#?}", *manager.get_environments_lazy()?))
+    let environments = manager.get_environments_lazy()?;
+    Ok(format!(
+        "External environment from: {}\n{:#?}",
+        environments.get_source(),
+        *environments
+    ))
 }
 
 fn dump_debugging_info(manager: &mut manager::Manager) -> Result<(), Box<dyn Error>> {
     let mut file = std::fs::OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .open(DEBUG_DUMP_PATH)?;
     let info = match get_environment_info(manager) {
@@ -50,7 +54,7 @@ _________________________________________
 }
 
 pub fn dump_info_if_needed(manager: &mut manager::Manager) {
-    if let Ok(_) = std::env::var(DEBUG_ENV_VAR) {
+    if std::env::var(DEBUG_ENV_VAR).is_ok() {
         if let Err(e) = dump_debugging_info(manager) {
             eprintln!(
                 "{}: Failed to dump debugging info: {}",
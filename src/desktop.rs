@@ -0,0 +1,498 @@
+use super::command;
+use super::open;
+use super::terminal;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// The parts of a desktop entry needed to launch it
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    /// Where the entry was read from, for %k
+    pub path: PathBuf,
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    /// The Exec key split into arguments, with field codes left in
+    pub exec: Vec<String>,
+    pub working_dir: Option<PathBuf>,
+    pub terminal: bool,
+}
+
+/// Returns the directories entries are searched for in, most important first
+/// These come from the cleaned environment, so the host's applications are found rather than
+///   the ones the snap adds to XDG_DATA_DIRS
+pub fn get_search_dirs(
+    data_home: Option<&OsStr>,
+    home: Option<&OsStr>,
+    data_dirs: Option<&OsStr>,
+) -> Vec<PathBuf> {
+    let data_home = match (data_home, home) {
+        (Some(data_home), _) if !data_home.is_empty() => Some(PathBuf::from(data_home)),
+        (_, Some(home)) => Some(Path::new(home).join(".local/share")),
+        _ => None,
+    };
+    let data_dirs = match data_dirs {
+        Some(data_dirs) if !data_dirs.is_empty() => data_dirs,
+        _ => OsStr::new("/usr/local/share:/usr/share"),
+    };
+    data_home
+        .into_iter()
+        .chain(std::env::split_paths(data_dirs))
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("applications"))
+        .collect()
+}
+
+/// Finds an entry from its desktop file ID (with or without .desktop) or its path
+pub fn find(id: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    if id.contains('/') {
+        let path = PathBuf::from(id);
+        return Some(path).filter(|path| path.is_file());
+    }
+    let file_name = if id.ends_with(".desktop") {
+        String::from(id)
+    } else {
+        format!("{}.desktop", id)
+    };
+    dirs.iter().find_map(|dir| find_in_dir(dir, &file_name))
+}
+
+/// Finds the file a desktop file ID refers to under one directory
+/// The ID of an entry in a subdirectory has the / replaced with -, so each - may also be a
+///   subdirectory (foo-bar.desktop may be foo/bar.desktop)
+fn find_in_dir(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    let path = dir.join(file_name);
+    if path.is_file() {
+        return Some(path);
+    }
+    file_name.match_indices('-').find_map(|(split, _)| {
+        let subdir = dir.join(&file_name[..split]);
+        if subdir.is_dir() {
+            find_in_dir(&subdir, &file_name[split + 1..])
+        } else {
+            None
+        }
+    })
+}
+
+/// Undoes the escapes allowed in string values
+fn unescape_value(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('s') => unescaped.push(' '),
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some('r') => unescaped.push('\r'),
+                Some('\\') => unescaped.push('\\'),
+                // Anything else isn't an escape here, but may be one in Exec
+                Some(other) => {
+                    unescaped.push('\\');
+                    unescaped.push(other);
+                }
+                None => unescaped.push('\\'),
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+/// Splits an Exec value into arguments, which are separated by spaces and may be quoted with
+///   double quotes, inside which ", `, $ and \ are escaped with a backslash
+fn split_exec(exec: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => args.extend(current.take()),
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) if "\"`$\\".contains(escaped) => arg.push(escaped),
+                            Some(other) => {
+                                arg.push('\\');
+                                arg.push(other);
+                            }
+                            None => bail!("Unterminated quote in Exec"),
+                        },
+                        Some(other) => arg.push(other),
+                        None => bail!("Unterminated quote in Exec"),
+                    }
+                }
+            }
+            _ => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+/// Reads the [Desktop Entry] group of a desktop file
+pub fn parse(path: &Path, text: &str) -> Result<Entry, Box<dyn Error>> {
+    let mut values = HashMap::new();
+    let mut in_entry = false;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            in_entry = line == "[Desktop Entry]";
+        } else if let (true, Some((key, value))) = (in_entry, line.split_once('=')) {
+            values
+                .entry(key.trim())
+                .or_insert_with(|| unescape_value(value.trim()));
+        }
+    }
+    match values.get("Type").map(String::as_str) {
+        Some("Application") => (),
+        Some(other) => bail!("{} is of type {}, not Application", path.display(), other),
+        None => bail!("{} has no [Desktop Entry] with a Type", path.display()),
+    }
+    let exec = match values.get("Exec") {
+        Some(exec) => split_exec(exec)?,
+        None => bail!("{} has no Exec key", path.display()),
+    };
+    if exec.is_empty() {
+        bail!("{} has an empty Exec key", path.display());
+    }
+    Ok(Entry {
+        path: path.to_owned(),
+        name: values.remove("Name"),
+        icon: values.remove("Icon").filter(|icon| !icon.is_empty()),
+        exec,
+        working_dir: values
+            .remove("Path")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from),
+        terminal: values.get("Terminal").map(String::as_str) == Some("true"),
+    })
+}
+
+/// Turns the files and URIs to open into what the field codes expand to, making paths absolute
+///   since the entry's working directory may be different
+/// Local files may be passed to %u and %U as paths, so the same values are used for all of them
+fn resolve_targets(targets: &[String], cwd: &Path) -> Vec<String> {
+    targets
+        .iter()
+        .map(|text| match open::resolve_target(text, cwd) {
+            open::Target::Path(path) | open::Target::Missing(path) => {
+                path.to_string_lossy().into_owned()
+            }
+            open::Target::Uri(uri) => uri,
+        })
+        .collect()
+}
+
+/// Returns the field codes in an argument without the %, leaving out %% (a literal %)
+fn get_field_codes(arg: &str) -> Vec<char> {
+    let mut codes = Vec::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            match chars.next() {
+                Some('%') | None => (),
+                Some(code) => codes.push(code),
+            }
+        }
+    }
+    codes
+}
+
+/// Expands the field codes in the entry's Exec, returning the command line of each instance to
+///   launch
+/// If the entry only takes one file or URI (%f or %u), an instance is launched for each
+pub fn expand(entry: &Entry, targets: &[String], cwd: &Path) -> Vec<Vec<String>> {
+    let targets = resolve_targets(targets, cwd);
+    let single = entry
+        .exec
+        .iter()
+        .any(|arg| get_field_codes(arg).iter().any(|code| "fu".contains(*code)));
+    let instances: Vec<usize> = if single && !targets.is_empty() {
+        (0..targets.len()).collect()
+    } else {
+        vec![0]
+    };
+    instances
+        .into_iter()
+        .map(|index| {
+            let mut argv = Vec::new();
+            for arg in &entry.exec {
+                match arg.as_str() {
+                    "%F" | "%U" => argv.extend(targets.iter().cloned()),
+                    // Without a file or URI the argument is left out rather than left empty
+                    "%f" | "%u" => argv.extend(targets.get(index).cloned()),
+                    "%i" => {
+                        if let Some(icon) = &entry.icon {
+                            argv.push(String::from("--icon"));
+                            argv.push(icon.clone());
+                        }
+                    }
+                    _ => argv.push(expand_arg(arg, entry, targets.get(index))),
+                }
+            }
+            argv
+        })
+        .collect()
+}
+
+/// Expands the field codes that stand for at most one value, dropping the rest
+fn expand_arg(arg: &str, entry: &Entry, target: Option<&String>) -> String {
+    let mut expanded = String::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some('f') | Some('u') => expanded.push_str(target.map_or("", String::as_str)),
+            Some('c') => expanded.push_str(entry.name.as_deref().unwrap_or("")),
+            Some('k') => expanded.push_str(&entry.path.to_string_lossy()),
+            _ => (),
+        }
+    }
+    expanded
+}
+
+/// Launches a desktop entry with the given files and URIs, detached so it outlives the snap app
+pub fn run(
+    id: &str,
+    targets: &[String],
+    vars: &[(OsString, Option<OsString>)],
+    env: &HashMap<OsString, OsString>,
+) -> i32 {
+    let dirs = get_search_dirs(
+        open::get_changed_value("XDG_DATA_HOME", vars, env).as_deref(),
+        open::get_changed_value("HOME", vars, env).as_deref(),
+        open::get_changed_value("XDG_DATA_DIRS", vars, env).as_deref(),
+    );
+    let path = match find(id, &dirs) {
+        Some(path) => path,
+        None => {
+            eprintln!(
+                "{}: Could not find desktop entry {}",
+                env!("CARGO_PKG_NAME"),
+                id
+            );
+            return command::EXIT_NOT_FOUND;
+        }
+    };
+    let entry = match std::fs::read_to_string(&path)
+        .map_err(|e| e.into())
+        .and_then(|text| parse(&path, &text))
+    {
+        Ok(entry) => entry,
+        Err(e) => {
+            eprintln!("{}: {}", env!("CARGO_PKG_NAME"), e);
+            return command::EXIT_NOT_EXECUTABLE;
+        }
+    };
+    let cwd = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+    let instances = expand(&entry, targets, &cwd);
+    if let Some(dir) = &entry.working_dir {
+        if let Err(e) = std::env::set_current_dir(dir) {
+            eprintln!(
+                "{}: Could not change to {}: {}",
+                env!("CARGO_PKG_NAME"),
+                dir.display(),
+                e
+            );
+            return command::EXIT_INTERNAL_ERROR;
+        }
+    }
+    let path_var = open::get_changed_value("PATH", vars, env);
+    let mode = command::Mode::Detach {
+        log: None,
+        pid_file: None,
+    };
+    let terminal = if entry.terminal {
+        match terminal::find(path_var.as_deref(), env) {
+            Some(terminal) => Some(terminal),
+            None => {
+                eprintln!(
+                    "{}: Could not find a terminal emulator to run {} in, set {} to the one to use",
+                    env!("CARGO_PKG_NAME"),
+                    path.display(),
+                    terminal::TERMINAL_ENV_VAR
+                );
+                return command::EXIT_NOT_FOUND;
+            }
+        }
+    } else {
+        None
+    };
+    let directory = std::env::current_dir().ok();
+    let mut exit_code = 0;
+    for argv in instances {
+        let result = match &terminal {
+            Some(terminal) => {
+                let args = terminal::get_args(terminal, directory.as_deref(), &argv);
+                command::run(&mode, &terminal.to_string_lossy(), None, args, vars)
+            }
+            None => command::run(&mode, &argv[0], None, &argv[1..], vars),
+        };
+        if result != 0 {
+            exit_code = result;
+        }
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = r#"[Desktop Entry]
+# A comment
+Type=Application
+Name=Merge Tool
+Name[de]=Zusammenführen
+Icon=merge
+Exec=merge --title "%c \"%%\"" %i %F
+Path=/tmp
+Terminal=false
+
+[Desktop Action new]
+Exec=other
+"#;
+
+    fn mock_entry(exec: &[&str]) -> Entry {
+        Entry {
+            path: PathBuf::from("/usr/share/applications/merge.desktop"),
+            name: Some(String::from("Merge Tool")),
+            icon: None,
+            exec: exec.iter().map(|arg| arg.to_string()).collect(),
+            working_dir: None,
+            terminal: false,
+        }
+    }
+
+    #[test]
+    fn parses_entry() {
+        let path = Path::new("/usr/share/applications/merge.desktop");
+        assert_eq!(
+            parse(path, ENTRY).expect("Failed to parse"),
+            Entry {
+                path: path.to_owned(),
+                name: Some(String::from("Merge Tool")),
+                icon: Some(String::from("merge")),
+                exec: vec!["merge", "--title", "%c \"%%\"", "%i", "%F"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                working_dir: Some(PathBuf::from("/tmp")),
+                terminal: false,
+            }
+        );
+        assert!(parse(path, "[Desktop Entry]\nType=Link\nExec=a").is_err());
+        assert!(parse(path, "[Desktop Entry]\nType=Application").is_err());
+        assert!(parse(path, "[Desktop Entry]\nType=Application\nExec=\"a").is_err());
+    }
+
+    #[test]
+    fn splits_exec() {
+        assert_eq!(
+            split_exec(r#"sh -c "echo \"\$HOME\" \\ `x`" a"b" "#).expect("Failed to split"),
+            vec!["sh", "-c", "echo \"$HOME\" \\ `x`", "ab"]
+        );
+        assert_eq!(
+            split_exec(&unescape_value(r"a\sb c\\\\d")).expect("Failed to split"),
+            vec!["a", "b", "c\\\\d"]
+        );
+        assert_eq!(split_exec(r#""""#).expect("Failed to split"), vec![""]);
+    }
+
+    #[test]
+    fn expands_field_codes() {
+        let cwd = Path::new("/home/alice");
+        let targets = vec![String::from("a.txt"), String::from("https://example.com")];
+        let mut entry = mock_entry(&["merge", "--title=%c", "%i", "%F", "%k", "%d"]);
+        entry.icon = Some(String::from("merge"));
+        assert_eq!(
+            expand(&entry, &targets, cwd),
+            vec![vec![
+                "merge",
+                "--title=Merge Tool",
+                "--icon",
+                "merge",
+                "/home/alice/a.txt",
+                "https://example.com",
+                "/usr/share/applications/merge.desktop",
+                "",
+            ]]
+        );
+        let entry = mock_entry(&["view", "%u", "100%%"]);
+        assert_eq!(
+            expand(&entry, &targets, cwd),
+            vec![
+                vec!["view", "/home/alice/a.txt", "100%"],
+                vec!["view", "https://example.com", "100%"],
+            ]
+        );
+        assert_eq!(expand(&entry, &[], cwd), vec![vec!["view", "100%"]]);
+        let entry = mock_entry(&["view", "%U"]);
+        assert_eq!(expand(&entry, &[], cwd), vec![vec!["view"]]);
+        // %%f is a literal %f, so every target goes to one instance
+        let entry = mock_entry(&["view", "--format=%%f", "%F"]);
+        assert_eq!(
+            expand(&entry, &targets, cwd),
+            vec![vec![
+                "view",
+                "--format=%f",
+                "/home/alice/a.txt",
+                "https://example.com"
+            ]]
+        );
+    }
+
+    #[test]
+    fn finds_entries() {
+        let dir =
+            std::env::temp_dir().join(format!("snap-out-desktop-test-{}", std::process::id()));
+        let dirs = get_search_dirs(
+            None,
+            Some(dir.as_os_str()),
+            Some(OsStr::new("relative:/nonexistent")),
+        );
+        assert_eq!(
+            dirs,
+            vec![
+                dir.join(".local/share/applications"),
+                PathBuf::from("/nonexistent/applications"),
+            ]
+        );
+        std::fs::create_dir_all(&dirs[0]).expect("Failed to create dir");
+        let path = dirs[0].join("merge.desktop");
+        std::fs::write(&path, ENTRY).expect("Failed to write entry");
+        assert_eq!(find("merge", &dirs), Some(path.clone()));
+        assert_eq!(find("merge.desktop", &dirs), Some(path.clone()));
+        assert_eq!(find(path.to_str().unwrap(), &[]), Some(path.clone()));
+        assert_eq!(find("other", &dirs), None);
+        let vendor = dirs[0].join("kde/org-example");
+        std::fs::create_dir_all(&vendor).expect("Failed to create dir");
+        std::fs::write(vendor.join("merge.desktop"), ENTRY).expect("Failed to write entry");
+        assert_eq!(
+            find("kde-org-example-merge", &dirs),
+            Some(vendor.join("merge.desktop"))
+        );
+        assert_eq!(find("kde-org-example-other", &dirs), None);
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+        assert_eq!(
+            get_search_dirs(None, None, None),
+            vec![
+                PathBuf::from("/usr/local/share/applications"),
+                PathBuf::from("/usr/share/applications"),
+            ]
+        );
+    }
+}
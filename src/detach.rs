@@ -0,0 +1,241 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// How many file descriptors to go through when the kernel can't mark them all at once
+const MAX_FDS: libc::c_int = 65536;
+
+/// Makes the file descriptors from first to last (inclusive) close when the command is executed
+fn set_cloexec(first: RawFd, last: RawFd) {
+    if first > last {
+        return;
+    }
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            first as libc::c_uint,
+            last as libc::c_uint,
+            libc::CLOSE_RANGE_CLOEXEC,
+        )
+    };
+    if result != 0 {
+        for fd in first..=last.min(MAX_FDS) {
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+    }
+}
+
+/// Makes every file descriptor except stdio and the sorted ones to keep close when the command is
+///   executed
+/// Closing them outright would also close the pipe std uses to report a failed exec
+fn close_inherited_fds(keep: &[RawFd]) {
+    let mut first = 3;
+    for &fd in keep.iter().filter(|&&fd| fd >= 3) {
+        set_cloexec(first, fd - 1);
+        first = fd + 1;
+    }
+    set_cloexec(first, RawFd::MAX);
+}
+
+fn open_output(log: Option<&Path>) -> Result<Stdio, Box<dyn Error>> {
+    Ok(match log {
+        Some(log) => std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(log)
+            .map_err(|e| format!("Could not open {}: {}", log.display(), e))?
+            .into(),
+        None => Stdio::null(),
+    })
+}
+
+/// Starts a command that is not a child of this process and is in a session of its own, so it
+///   keeps running once whatever started snap-out exits or is killed
+/// The command's stdin is /dev/null and its output goes to the log if one is given, otherwise
+///   also /dev/null. Other file descriptors are closed, except for the ones in keep_fds. Returns
+///   the command's PID once it has been executed, or the error that stopped it from being
+///   executed.
+pub fn run(
+    mut command: Command,
+    log: Option<&Path>,
+    pid_file: Option<&Path>,
+    keep_fds: &[RawFd],
+) -> Result<u32, Box<dyn Error>> {
+    let mut keep_fds = keep_fds.to_vec();
+    keep_fds.sort_unstable();
+    command.stdin(Stdio::null());
+    command.stdout(open_output(log)?);
+    command.stderr(open_output(log)?);
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let (mut pid_reader, pid_writer) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    let writer_fd = pid_writer.as_raw_fd();
+    // Runs in the child std forks. It starts a new session then forks again, so the command
+    //   isn't a session leader and can never get a controlling terminal. Only the grandchild goes
+    //   on to execute the command, after sending its PID back through the pipe.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setsid() < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            match libc::fork() {
+                -1 => Err(std::io::Error::last_os_error()),
+                0 => {
+                    close_inherited_fds(&keep_fds);
+                    Ok(())
+                }
+                pid => {
+                    let bytes = pid.to_ne_bytes();
+                    libc::write(
+                        writer_fd,
+                        bytes.as_ptr() as *const libc::c_void,
+                        bytes.len(),
+                    );
+                    libc::_exit(0);
+                }
+            }
+        });
+    }
+    // std waits until the command is executed (or fails to be) before returning
+    let result = command.spawn().and_then(|mut child| child.wait());
+    drop(pid_writer);
+    result?;
+    let mut bytes = [0; 4];
+    pid_reader.read_exact(&mut bytes)?;
+    let pid = i32::from_ne_bytes(bytes) as u32;
+    if let Some(pid_file) = pid_file {
+        std::fs::write(pid_file, format!("{}\n", pid))
+            .map_err(|e| format!("Could not write {}: {}", pid_file.display(), e))?;
+    }
+    Ok(pid)
+}
+
+/// Starts a command in a session of its own and waits up to the given time for it to exit
+/// Returns its exit status if it exited in time, or None if it is still running, in which case it
+///   is left to run on its own. Like a detached command, its stdin is /dev/null and other file
+///   descriptors except stdout, stderr and the ones in keep_fds are closed, so it has nothing
+///   tying it to this process's terminal.
+pub fn start_in_session(
+    mut command: Command,
+    keep_fds: &[RawFd],
+    wait: Duration,
+) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + wait;
+    let mut keep_fds = keep_fds.to_vec();
+    keep_fds.sort_unstable();
+    command.stdin(Stdio::null());
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setsid() < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            close_inherited_fds(&keep_fds);
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    loop {
+        match child.try_wait()? {
+            None if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            status => return Ok(status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_for(path: &Path) {
+        let start = Instant::now();
+        while !path.exists() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn detaches_command() {
+        let dir = std::env::temp_dir().join(format!("snap-out-detach-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let log = dir.join("log");
+        let pid_file = dir.join("pid");
+        let done = dir.join("done");
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "echo \"$$ $(ps -o sid= -p $$) $(ls /proc/self/fd | wc -l)\"; touch \"$0\"",
+        ]);
+        command.arg(&done);
+        let pid = run(command, Some(&log), Some(&pid_file), &[]).expect("Failed to detach");
+        wait_for(&done);
+        let written = std::fs::read_to_string(&pid_file).expect("Failed to read PID file");
+        assert_eq!(written, format!("{}\n", pid));
+        let output = std::fs::read_to_string(&log).expect("Failed to read log");
+        let fields: Vec<&str> = output.split_whitespace().collect();
+        assert_eq!(fields[0], pid.to_string());
+        // It isn't the leader of its session, and only has stdio (plus the one ls opens)
+        assert_ne!(fields[1], pid.to_string());
+        assert_eq!(fields[2], "4");
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn keeps_given_fds() {
+        let dir =
+            std::env::temp_dir().join(format!("snap-out-keep-fd-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let output = dir.join("output");
+        let done = dir.join("done");
+        let file = File::create(&output).expect("Failed to create output");
+        // Unlike the original, the duplicate isn't closed on exec
+        let fd = unsafe { libc::dup(file.as_raw_fd()) };
+        assert!(fd >= 0);
+        let mut command = Command::new("sh");
+        command.args(["-c", &format!("echo kept >&{}; touch \"$0\"", fd)]);
+        command.arg(&done);
+        let result = run(command, None, None, &[fd]);
+        unsafe { libc::close(fd) };
+        result.expect("Failed to detach");
+        wait_for(&done);
+        let written = std::fs::read_to_string(&output).expect("Failed to read output");
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+        assert_eq!(written, "kept\n");
+    }
+
+    #[test]
+    fn waits_briefly_in_new_session() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "[ \"$(ps -o sid= -p $$)\" -eq $$ ] && exit 3"]);
+        let status = start_in_session(command, &[], Duration::from_secs(10))
+            .expect("Failed to run")
+            .expect("Should have exited");
+        assert_eq!(status.code(), Some(3));
+        let start = Instant::now();
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let status = start_in_session(command, &[], Duration::from_millis(100));
+        assert!(status.expect("Failed to run").is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reports_exec_errors() {
+        let command = Command::new("snap-out-missing-command");
+        let error = run(command, None, None, &[]).expect_err("Should have failed");
+        let error = error
+            .downcast_ref::<std::io::Error>()
+            .expect("Should be an IO error");
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+}
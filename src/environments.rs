@@ -1,60 +1,375 @@
+use super::fallback::{self, Source};
+use super::locations::Locations;
 use super::process;
-use super::variable::Variable;
+use super::sandbox::{Sandbox, Signal};
+use super::variable::{self, Variable};
 use std::collections::HashMap;
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-/// The environment variable used to determine if the snap variables have been set
-const SNAP_SENTINEL_VAR: &str = "SNAP";
+/// The environment of a single process, along with what is needed to identify it
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+    pub pid: i32,
+    /// When the process started, see process::Process::get_start_time()
+    pub start_time: u64,
+    pub comm: String,
+    pub exe: Option<PathBuf>,
+    pub cmdline: Vec<String>,
+    pub cgroups: Vec<String>,
+    /// What showed the process is inside the snap, or None if it is outside
+    pub inside: Option<Signal>,
+    /// If the process is running an executable or script shipped in the snap
+    pub snap_owned: bool,
+    pub env: Rc<HashMap<OsString, OsString>>,
+}
+
+impl Snapshot {
+    /// Reads everything about a process, without yet deciding if it is inside the snap
+    pub fn from_process(process: &dyn process::Process) -> Self {
+        Snapshot {
+            pid: process.get_pid(),
+            start_time: process.get_start_time(),
+            comm: process.get_comm(),
+            exe: process.get_exe(),
+            cmdline: process.get_cmdline(),
+            cgroups: process.get_cgroups(),
+            inside: None,
+            snap_owned: false,
+            env: process.get_env(),
+        }
+    }
+
+    /// Stands in for the process that launched the snap when it could not be found, with the pid
+    ///   set to 0 and the source used as the name
+    pub fn from_source(source: Source, env: HashMap<OsString, OsString>) -> Self {
+        Snapshot {
+            pid: 0,
+            start_time: 0,
+            comm: source.to_string(),
+            exe: None,
+            cmdline: Vec::new(),
+            cgroups: Vec::new(),
+            inside: None,
+            snap_owned: false,
+            env: Rc::new(env),
+        }
+    }
 
-/// The environment of all relevant processes:
+    pub fn is_inside(&self) -> bool {
+        self.inside.is_some()
+    }
+
+    /// Returns if the process runs an interpreter, in which case its first argument that isn't an
+    ///   option is the script it runs
+    fn is_interpreter(&self) -> bool {
+        let program = match (&self.exe, self.cmdline.first()) {
+            (Some(exe), _) => exe.file_name(),
+            (None, Some(argv0)) => Path::new(argv0).file_name(),
+            (None, None) => None,
+        };
+        program.and_then(OsStr::to_str).is_some_and(|name| {
+            INTERPRETERS.contains(&name) || name.starts_with("python") || name.starts_with("perl")
+        })
+    }
+
+    /// Returns if the process's executable, or the script its interpreter was given, is in one of
+    ///   the given locations
+    fn runs_from(&self, locations: &Locations) -> bool {
+        if let Some(exe) = &self.exe {
+            if locations.contains(exe.as_os_str()) {
+                return true;
+            }
+        }
+        let mut args = self.cmdline.iter();
+        if let Some(argv0) = args.next() {
+            if locations.contains(OsStr::new(argv0)) {
+                return true;
+            }
+        }
+        if !self.is_interpreter() {
+            return false;
+        }
+        match args.find(|arg| !arg.starts_with('-')) {
+            Some(script) => locations.contains(OsStr::new(script)),
+            None => false,
+        }
+    }
+}
+
+/// Programs that run a script given as an argument, besides any python or perl version
+const INTERPRETERS: &[&str] = &["sh", "bash", "dash", "node"];
+
+/// Environment variable that sets which snaps to escape when one snap was launched from another
+pub const NESTING_ENV_VAR: &str = "SNAP_OUT_NESTING";
+
+/// Which snaps to escape when one snap was launched from inside another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nesting {
+    /// Escape only the innermost snap, keeping the environment of the snap that launched it
+    Innermost,
+    /// Escape every snap, back to the first process that is not in any
+    All,
+}
+
+impl Nesting {
+    /// Reads the nesting mode from NESTING_ENV_VAR, defaulting to All
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        match std::env::var(NESTING_ENV_VAR) {
+            Err(_) => Ok(Nesting::All),
+            Ok(value) => match value.as_str() {
+                "" | "all" => Ok(Nesting::All),
+                "innermost" => Ok(Nesting::Innermost),
+                _ => bail!(
+                    "Invalid value {:?} for {}, expected innermost or all",
+                    value,
+                    NESTING_ENV_VAR
+                ),
+            },
+        }
+    }
+}
+
+/// The environment of all relevant processes (here "snap" refers to whichever sandbox is in use):
 /// - The process that launched the snap (no snap variables)
 /// - The first process inside the snap (has snap variables)
 /// - The current process (has snap variables, plus modifications not made by the snap that we want)
+///
+/// Processes that run something shipped in the snap (such as desktop-launch or wrapper scripts)
+///   directly after the first process inside the snap are considered part of the snap, so their
+///   changes are undone as well. Only changes made by the current process's parent (our own app)
+///   and anything after it are kept.
+///
+/// Also keeps every process between them, ordered from the process that launched the snap to the
+///   current process
+///
+/// If the process that launched the snap is gone, the external environment is rebuilt from other
+///   sources, and source records which one was used
 #[derive(Debug)]
 pub struct All {
+    source: Source,
     external: Rc<HashMap<OsString, OsString>>,
     snap: Rc<HashMap<OsString, OsString>>,
     myself: Rc<HashMap<OsString, OsString>>,
+    chain: Vec<Rc<Snapshot>>,
+    sandbox: Box<dyn Sandbox>,
+    warnings: Vec<String>,
 }
 
 impl All {
-    /// Detects relevant environments
-    pub fn detect(mut process: Box<dyn process::Process>) -> Result<Self, Box<dyn Error>> {
-        let mut envs = Vec::new();
-        let mut pids = Vec::new();
+    /// Detects relevant environments, using the given sandbox to decide which processes are in it
+    /// A process whose instance name differs from the innermost snap's is in another snap, which is
+    ///   treated as outside when only escaping the innermost snap
+    /// If the top of the process tree is reached while still inside, rebuild is used instead
+    /// Processes whose environment can't be read are skipped, and if the walk can't continue past
+    ///   one, rebuild is used to get a partial result; both are recorded as warnings
+    pub fn detect(
+        mut process: Box<dyn process::Process>,
+        sandbox: Box<dyn Sandbox>,
+        nesting: Nesting,
+        rebuild: fallback::Rebuild,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut chain: Vec<Snapshot> = Vec::new();
+        let mut warnings = Vec::new();
+        let mut source = Source::Ancestor;
+        let mut innermost: Option<String> = None;
         loop {
-            envs.push(process.get_env());
-            pids.push(process.get_pid());
-            if !envs
-                .last()
-                .unwrap()
-                .contains_key(OsStr::new(SNAP_SENTINEL_VAR))
-            {
-                break;
+            let mut snapshot = Snapshot::from_process(&*process);
+            if let Some(error) = process.get_env_error() {
+                if chain.is_empty() {
+                    bail!("Could not read own environment: {}", error);
+                }
+                // Without its environment there's no telling if it's inside, so look past it
+                warnings.push(format!(
+                    "Skipped pid {} ({}), could not read its environment: {}",
+                    snapshot.pid, snapshot.comm, error
+                ));
+            } else {
+                snapshot.inside = sandbox.get_signal(&snapshot, &chain);
+                if snapshot.is_inside() && nesting == Nesting::Innermost {
+                    match (sandbox.get_instance_name(&snapshot.env), &innermost) {
+                        (Some(name), Some(innermost)) if name != *innermost => {
+                            snapshot.inside = None
+                        }
+                        (Some(name), None) => innermost = Some(name),
+                        _ => (),
+                    }
+                }
+                let inside = snapshot.is_inside();
+                chain.push(snapshot);
+                if !inside {
+                    break;
+                }
             }
-            process = match process.get_parent()? {
+            let parent = match process::get_verified_parent(&*process) {
+                Ok(parent) => parent,
+                Err(e) => {
+                    warnings.push(format!(
+                        "Could not look up the parent of pid {}: {}",
+                        process.get_pid(),
+                        e
+                    ));
+                    None
+                }
+            };
+            process = match parent {
                 Some(p) => p,
-                None => bail!(
-                    "Could not find a process outside of the snap (searched PIDs {:?})",
-                    pids
-                ),
+                None => match rebuild(chain.last().unwrap()) {
+                    Some((rebuilt_from, env)) => {
+                        source = rebuilt_from;
+                        chain.push(Snapshot::from_source(source, env));
+                        break;
+                    }
+                    None => bail!(
+                        "Could not find a process outside of the snap (searched PIDs {:?})",
+                        chain.iter().map(|s| s.pid).collect::<Vec<_>>()
+                    ),
+                },
             }
         }
-        if envs.len() < 2 {
-            bail!("Not inside a snap");
+        chain.reverse();
+        Self::from_chain(chain, sandbox, source, warnings)
+    }
+
+    /// Builds the environments from one recorded when the snap was launched, without looking
+    ///   through the ancestors, with the process that recorded it as the boundary
+    /// That is more reliable than the ancestors found later on, as apps such as Electron ones
+    ///   reparent their processes so the ancestors may no longer lead to the launcher.
+    pub fn from_recording(
+        mut launch: Snapshot,
+        mut myself: Snapshot,
+        sandbox: Box<dyn Sandbox>,
+        env: HashMap<OsString, OsString>,
+    ) -> Result<Self, Box<dyn Error>> {
+        myself.inside = sandbox.get_signal(&myself, &[]);
+        let mut chain = vec![Snapshot::from_source(Source::Recorded, env)];
+        if launch.pid != myself.pid {
+            launch.inside = sandbox.get_signal(&launch, std::slice::from_ref(&myself));
+            chain.push(launch);
+        }
+        chain.push(myself);
+        Self::from_chain(chain, sandbox, Source::Recorded, Vec::new())
+    }
+
+    /// Works out the relevant environments from the processes found, ordered from the process
+    ///   that launched the snap to the current process, with inside already set on each
+    pub fn from_chain(
+        mut chain: Vec<Snapshot>,
+        sandbox: Box<dyn Sandbox>,
+        source: Source,
+        warnings: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if chain.len() < 2 {
+            bail!("Not inside a {}", sandbox.get_name());
         }
-        let myself = envs[0].clone();
-        let external = envs.last().unwrap().clone();
-        let snap = envs.iter().rev().skip(1).next().unwrap().clone();
+        let mut locations = Locations::default();
+        for snapshot in chain.iter().filter(|s| s.is_inside()) {
+            locations.merge(sandbox.get_locations(&snapshot.env));
+        }
+        for snapshot in &mut chain {
+            snapshot.snap_owned = snapshot.is_inside() && snapshot.runs_from(&locations);
+        }
+        // Extend the snap through the processes it owns, but never include the current process
+        let mut snap_index = 1;
+        while snap_index + 2 < chain.len() && chain[snap_index + 1].snap_owned {
+            snap_index += 1;
+        }
+        let chain: Vec<Rc<Snapshot>> = chain.into_iter().map(Rc::new).collect();
         Ok(All {
-            external,
-            snap,
-            myself,
+            source,
+            external: chain[0].env.clone(),
+            snap: chain[snap_index].env.clone(),
+            myself: chain.last().unwrap().env.clone(),
+            chain,
+            sandbox,
+            warnings,
         })
     }
 
+    /// Returns problems found while looking for the process that launched the snap which didn't
+    ///   stop detection, but may make the result less accurate
+    pub fn get_warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns where the environment of the process that launched the snap came from
+    pub fn get_source(&self) -> Source {
+        self.source
+    }
+
+    /// Returns every process considered, from the process that launched the snap to this one
+    pub fn get_chain(&self) -> &[Rc<Snapshot>] {
+        &self.chain
+    }
+
+    /// Returns the first process inside the snap, whose parent is outside of it
+    pub fn get_boundary(&self) -> &Snapshot {
+        &self.chain[1]
+    }
+
+    /// Returns the environment of the process that launched the snap
+    pub fn get_external(&self) -> &HashMap<OsString, OsString> {
+        &self.external
+    }
+
+    /// Returns the directories belonging to the snap, as seen by every process inside it (which
+    ///   covers each snap being escaped when they are nested)
+    pub fn get_snap_locations(&self) -> Locations {
+        let mut locations = self.sandbox.get_locations(&self.snap);
+        for snapshot in self.chain.iter().filter(|s| s.is_inside()) {
+            locations.merge(self.sandbox.get_locations(&snapshot.env));
+        }
+        locations
+    }
+
+    /// Returns the kind of sandbox being escaped
+    pub fn get_sandbox(&self) -> &dyn Sandbox {
+        &*self.sandbox
+    }
+
+    /// Returns a human readable name of the sandbox, such as "snap"
+    pub fn get_sandbox_name(&self) -> &'static str {
+        self.sandbox.get_name()
+    }
+
+    /// Returns the directory the sandbox is mounted at
+    pub fn get_sandbox_root(&self) -> Option<PathBuf> {
+        self.sandbox.get_root(&self.snap)
+    }
+
+    /// Returns the name of the app inside the sandbox
+    pub fn get_app_name(&self) -> Option<String> {
+        self.sandbox.get_app_name(&self.snap)
+    }
+
+    /// Returns what identifies this install of the app, such as the snap instance name
+    pub fn get_instance_name(&self) -> Option<String> {
+        self.sandbox.get_instance_name(&self.myself)
+    }
+
+    /// Returns what is known about a variable, if the sandbox is known to use it
+    pub fn lookup(&self, name: &OsStr) -> Option<&'static variable::KnownVariable> {
+        variable::lookup(name, self.sandbox.get_catalog())
+    }
+
+    /// Returns the values a variable held, each paired with the process that changed it to that
+    ///   value, starting with the process that launched the snap
+    fn get_history(&self, key: &OsStr) -> Vec<variable::Change> {
+        let mut history: Vec<variable::Change> = Vec::new();
+        for snapshot in &self.chain {
+            let value = snapshot.env.get(key).cloned();
+            if history.last().map(|change| &change.value) != Some(&value) {
+                history.push(variable::Change {
+                    process: snapshot.clone(),
+                    value,
+                });
+            }
+        }
+        history
+    }
+
     pub fn consolidate(&self) -> HashMap<OsString, Variable> {
         let mut result = HashMap::new();
         for key in std::iter::empty()
@@ -66,10 +381,12 @@ impl All {
                 result.insert(
                     key.clone(),
                     Variable::new(
-                        self.external.get(key).map(Clone::clone),
-                        self.snap.get(key).map(Clone::clone),
-                        self.myself.get(key).map(Clone::clone),
-                    ),
+                        self.external.get(key).cloned(),
+                        self.snap.get(key).cloned(),
+                        self.myself.get(key).cloned(),
+                    )
+                    .with_known(self.lookup(key))
+                    .with_history(self.get_history(key)),
                 );
             }
         }
@@ -79,12 +396,18 @@ impl All {
 
 #[cfg(test)]
 mod tests {
+    use super::super::process::mock::mock_env;
+    use super::super::sandbox;
     use super::*;
 
+    fn no_rebuild(_: &Snapshot) -> Option<(Source, HashMap<OsString, OsString>)> {
+        None
+    }
+
     fn assert_maps_to(map: &HashMap<OsString, OsString>, key: &str, val: Option<&str>) {
         assert_eq!(
             map.get(&OsString::from(key)),
-            val.map(|v| OsString::from(v)).as_ref()
+            val.map(OsString::from).as_ref()
         );
     }
 
@@ -94,7 +417,12 @@ mod tests {
             vec![("USER", "alice"), ("OUTSIDE", "1")],
             vec![("USER", "alice"), ("SNAP", "/snap"), ("INSIDE", "2")],
         ]);
-        let result = All::detect(Box::new(process));
+        let result = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        );
         if let Ok(envs) = result {
             assert_maps_to(&envs.external, "OUTSIDE", Some("1"));
             assert_maps_to(&envs.external, "INSIDE", None);
@@ -105,7 +433,7 @@ mod tests {
             assert_maps_to(&envs.myself, "OUTSIDE", None);
             assert_maps_to(&envs.myself, "INSIDE", Some("2"));
         } else {
-            assert!(false);
+            panic!("Failed to detect environments");
         }
     }
 
@@ -118,13 +446,18 @@ mod tests {
             vec![("USER", "alice"), ("SNAP", "/snap")],
             vec![("USER", "alice"), ("SNAP", "/snap"), ("MYSELF", "3")],
         ]);
-        let result = All::detect(Box::new(process));
+        let result = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        );
         if let Ok(envs) = result {
             assert_maps_to(&envs.external, "OUTSIDE", Some("1"));
             assert_maps_to(&envs.snap, "EDGE", Some("2"));
             assert_maps_to(&envs.myself, "MYSELF", Some("3"));
         } else {
-            assert!(false);
+            panic!("Failed to detect environments");
         }
     }
 
@@ -134,7 +467,12 @@ mod tests {
             vec![("USER", "alice")],
             vec![("USER", "alice"), ("DISPLAY", ":0")],
         ]);
-        let result = All::detect(Box::new(process));
+        let result = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        );
         if let Ok(result) = result {
             panic!("Should have detected it was not in the snap: {:#?}", result)
         }
@@ -146,7 +484,12 @@ mod tests {
             vec![("USER", "alice"), ("SNAP", "/snap")],
             vec![("USER", "alice"), ("DISPLAY", ":0"), ("SNAP", "/snap")],
         ]);
-        let result = All::detect(Box::new(process));
+        let result = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        );
         if let Ok(result) = result {
             panic!(
                 "Should have been unable to find the edge of the snap: {:#?}",
@@ -155,6 +498,429 @@ mod tests {
         }
     }
 
+    #[test]
+    fn treats_snap_owned_wrappers_as_snap() {
+        let process = process::mock::MockProcess::with_cmdlines(vec![
+            (vec!["/usr/bin/bash"], vec![("OUTSIDE", "1")]),
+            (
+                vec!["/snap/foo/1/bin/desktop-launch"],
+                vec![("SNAP", "/snap/foo/1"), ("EDGE", "2")],
+            ),
+            (
+                vec!["/usr/bin/bash", "-e", "/snap/foo/1/bin/wrapper.sh"],
+                vec![("SNAP", "/snap/foo/1"), ("EDGE", "2"), ("LAUNCH", "3")],
+            ),
+            (
+                vec!["/snap/foo/1/usr/bin/app"],
+                vec![("SNAP", "/snap/foo/1"), ("WRAPPER", "4")],
+            ),
+            (
+                vec!["/snap/foo/1/bin/snap-out"],
+                vec![("SNAP", "/snap/foo/1"), ("WRAPPER", "4"), ("APP", "5")],
+            ),
+        ]);
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_maps_to(&envs.snap, "WRAPPER", Some("4"));
+        assert_maps_to(&envs.snap, "APP", None);
+        assert!(envs.chain.iter().skip(1).all(|s| s.snap_owned));
+    }
+
+    #[test]
+    fn only_follows_arguments_of_interpreters() {
+        let process = process::mock::MockProcess::with_cmdlines(vec![
+            (vec!["/usr/bin/bash"], vec![("OUTSIDE", "1")]),
+            (
+                vec!["/snap/foo/1/bin/launcher"],
+                vec![("SNAP", "/snap/foo/1"), ("EDGE", "2")],
+            ),
+            (
+                vec!["/usr/bin/less", "/snap/foo/1/README"],
+                vec![("SNAP", "/snap/foo/1"), ("EDGE", "2"), ("APP", "3")],
+            ),
+            (
+                vec!["/usr/bin/python3.12", "-u", "/snap/foo/1/bin/tool.py"],
+                vec![("SNAP", "/snap/foo/1"), ("APP", "3")],
+            ),
+            (
+                vec!["/snap/foo/1/bin/snap-out"],
+                vec![("SNAP", "/snap/foo/1"), ("APP", "3")],
+            ),
+        ]);
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert!(!envs.chain[2].snap_owned);
+        assert!(envs.chain[3].snap_owned);
+        assert_maps_to(&envs.snap, "APP", None);
+    }
+
+    #[test]
+    fn stops_snap_at_external_executable() {
+        let process = process::mock::MockProcess::with_cmdlines(vec![
+            (vec!["/usr/bin/bash"], vec![("OUTSIDE", "1")]),
+            (
+                vec!["/snap/code/1/usr/share/code/code"],
+                vec![("SNAP", "/snap/code/1"), ("EDGE", "2")],
+            ),
+            (
+                vec!["/usr/bin/bash"],
+                vec![("SNAP", "/snap/code/1"), ("EDGE", "2"), ("CODE", "3")],
+            ),
+            (
+                vec!["/snap/code/1/usr/share/code/bin/helper"],
+                vec![("SNAP", "/snap/code/1"), ("CODE", "3"), ("USER", "4")],
+            ),
+            (
+                vec!["/snap/code/1/bin/snap-out"],
+                vec![("SNAP", "/snap/code/1"), ("CODE", "3"), ("USER", "4")],
+            ),
+        ]);
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_maps_to(&envs.snap, "EDGE", Some("2"));
+        assert_maps_to(&envs.snap, "CODE", None);
+        assert!(!envs.chain[2].snap_owned);
+    }
+
+    #[test]
+    fn detects_appimage() {
+        let process = process::mock::MockProcess::with_cmdlines(vec![
+            (vec!["/usr/bin/bash"], vec![("PATH", "/usr/bin")]),
+            (
+                vec!["/tmp/.mount_fooAbC/AppRun"],
+                vec![("APPDIR", "/tmp/.mount_fooAbC"), ("PATH", "/usr/bin")],
+            ),
+            (
+                vec!["/tmp/.mount_fooAbC/usr/bin/foo"],
+                vec![
+                    ("APPDIR", "/tmp/.mount_fooAbC"),
+                    ("PATH", "/tmp/.mount_fooAbC/usr/bin:/usr/bin"),
+                ],
+            ),
+            (
+                vec!["/usr/bin/snap-out"],
+                vec![
+                    ("APPDIR", "/tmp/.mount_fooAbC"),
+                    ("PATH", "/tmp/.mount_fooAbC/usr/bin:/usr/bin"),
+                ],
+            ),
+        ]);
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::AppImage),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_maps_to(&envs.external, "APPDIR", None);
+        assert_maps_to(
+            &envs.snap,
+            "PATH",
+            Some("/tmp/.mount_fooAbC/usr/bin:/usr/bin"),
+        );
+        assert_eq!(envs.get_app_name(), None);
+        assert_eq!(
+            envs.get_sandbox_root(),
+            Some(PathBuf::from("/tmp/.mount_fooAbC"))
+        );
+    }
+
+    #[test]
+    fn detects_boundary_when_snap_variable_is_unset() {
+        let scope = "/user.slice/user@1000.service/app.slice/snap.foo.foo-1a2b.scope";
+        let process = process::mock::MockProcess::with_info(vec![
+            process::mock::MockInfo {
+                cmdline: vec!["/usr/bin/bash"],
+                cgroup: "/user.slice/user@1000.service/app.slice/vte-spawn-1a2b.scope",
+                env: vec![("OUTSIDE", "1")],
+            },
+            process::mock::MockInfo {
+                cmdline: vec!["/snap/foo/1/usr/bin/foo"],
+                cgroup: scope,
+                env: vec![("SNAP", "/snap/foo/1"), ("EDGE", "2")],
+            },
+            process::mock::MockInfo {
+                cmdline: vec!["/usr/bin/bash"],
+                cgroup: scope,
+                env: vec![("EDGE", "2")],
+            },
+            process::mock::MockInfo {
+                cmdline: vec!["/usr/bin/snap-out"],
+                cgroup: scope,
+                env: vec![("EDGE", "2"), ("MYSELF", "3")],
+            },
+        ]);
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_maps_to(&envs.external, "OUTSIDE", Some("1"));
+        assert_maps_to(&envs.snap, "EDGE", Some("2"));
+        assert_eq!(envs.get_boundary().inside, Some(Signal::Environment));
+        assert_eq!(envs.chain[2].inside, Some(Signal::Cgroup));
+    }
+
+    #[test]
+    fn rebuilds_external_when_top_is_inside() {
+        let process = process::mock::MockProcess::new(vec![
+            vec![("SNAP", "/snap/foo/1"), ("HOME", "/home/alice/snap/foo/1")],
+            vec![("SNAP", "/snap/foo/1"), ("MYSELF", "1")],
+        ]);
+        let rebuild = |process: &Snapshot| {
+            assert_maps_to(&process.env, "HOME", Some("/home/alice/snap/foo/1"));
+            let mut external = HashMap::new();
+            external.insert(OsString::from("HOME"), OsString::from("/home/alice"));
+            Some((Source::EnvironmentFiles, external))
+        };
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_eq!(envs.get_source(), Source::EnvironmentFiles);
+        assert_maps_to(&envs.external, "HOME", Some("/home/alice"));
+        assert_eq!(envs.get_boundary().pid, 100);
+        assert_eq!(envs.chain[0].pid, 0);
+        assert_eq!(envs.chain[0].comm, "environment files");
+    }
+
+    #[test]
+    fn skips_unreadable_ancestors() {
+        use process::mock::MockAncestor::*;
+        let process = process::mock::MockProcess::with_ancestors(vec![
+            Readable(vec![("OUTSIDE", "1")]),
+            Unreadable,
+            Readable(vec![("SNAP", "/snap/foo/1"), ("EDGE", "2")]),
+            Unreadable,
+            Readable(vec![("SNAP", "/snap/foo/1"), ("MYSELF", "3")]),
+        ]);
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_eq!(envs.get_source(), Source::Ancestor);
+        assert_maps_to(&envs.external, "OUTSIDE", Some("1"));
+        assert_maps_to(&envs.snap, "EDGE", Some("2"));
+        assert_maps_to(&envs.myself, "MYSELF", Some("3"));
+        assert_eq!(
+            envs.chain.iter().map(|s| s.pid).collect::<Vec<_>>(),
+            vec![100, 102, 104]
+        );
+        assert_eq!(envs.get_warnings().len(), 2);
+        assert!(envs.get_warnings()[0].contains("pid 103"));
+    }
+
+    #[test]
+    fn builds_from_recording() {
+        let mut snapshots: Vec<Snapshot> = vec![
+            vec![("SNAP", "/snap/foo/1"), ("SNAP_NAME", "foo"), ("EDGE", "1")],
+            vec![
+                ("SNAP", "/snap/foo/1"),
+                ("SNAP_NAME", "foo"),
+                ("MYSELF", "2"),
+            ],
+        ]
+        .into_iter()
+        .map(|vars| Snapshot::from_source(Source::Recorded, mock_env(vars)))
+        .collect();
+        snapshots[0].pid = 101;
+        snapshots[1].pid = 105;
+        let myself = snapshots.pop().unwrap();
+        let launch = snapshots.pop().unwrap();
+        let mut recorded = HashMap::new();
+        recorded.insert(OsString::from("LAUNCHER"), OsString::from("3"));
+        let envs = All::from_recording(launch, myself, Box::new(sandbox::Snap), recorded)
+            .expect("Failed to build environments");
+        assert_eq!(envs.get_source(), Source::Recorded);
+        assert_maps_to(&envs.external, "LAUNCHER", Some("3"));
+        assert_maps_to(&envs.snap, "EDGE", Some("1"));
+        assert_maps_to(&envs.myself, "MYSELF", Some("2"));
+        assert_eq!(envs.get_boundary().pid, 101);
+        assert_eq!(envs.chain[0].comm, "recording");
+        assert_eq!(envs.get_instance_name(), Some(String::from("foo")));
+    }
+
+    #[test]
+    fn rebuilds_external_when_parent_pid_was_reused() {
+        use process::mock::MockAncestor::*;
+        let process = process::mock::MockProcess::with_ancestors(vec![
+            Reused(vec![("OUTSIDE", "1")]),
+            Readable(vec![("SNAP", "/snap/foo/1"), ("EDGE", "2")]),
+            Readable(vec![("SNAP", "/snap/foo/1"), ("MYSELF", "3")]),
+        ]);
+        let rebuild = |_: &Snapshot| Some((Source::EnvironmentFiles, HashMap::new()));
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_eq!(envs.get_source(), Source::EnvironmentFiles);
+        assert_maps_to(&envs.external, "OUTSIDE", None);
+        assert_eq!(envs.get_warnings().len(), 1);
+    }
+
+    #[test]
+    fn rebuilds_external_when_parent_is_hidden() {
+        use process::mock::MockAncestor::*;
+        let process = process::mock::MockProcess::with_ancestors(vec![
+            Hidden,
+            Readable(vec![("SNAP", "/snap/foo/1"), ("EDGE", "2")]),
+            Readable(vec![("SNAP", "/snap/foo/1"), ("MYSELF", "3")]),
+        ]);
+        let rebuild = |_: &Snapshot| {
+            let mut external = HashMap::new();
+            external.insert(OsString::from("OUTSIDE"), OsString::from("1"));
+            Some((Source::EnvironmentFiles, external))
+        };
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_eq!(envs.get_source(), Source::EnvironmentFiles);
+        assert_maps_to(&envs.external, "OUTSIDE", Some("1"));
+        assert_eq!(envs.get_boundary().pid, 101);
+        assert_eq!(
+            envs.get_warnings(),
+            ["Could not look up the parent of pid 101: No such process"]
+        );
+        let process = process::mock::MockProcess::with_ancestors(vec![
+            Hidden,
+            Readable(vec![("SNAP", "/snap/foo/1")]),
+        ]);
+        assert!(All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .is_err());
+    }
+
+    fn mock_nested_snaps() -> process::mock::MockProcess {
+        process::mock::MockProcess::new(vec![
+            vec![("OUTSIDE", "1")],
+            vec![
+                ("SNAP", "/snap/code/1"),
+                ("SNAP_NAME", "code"),
+                ("CODE", "2"),
+            ],
+            vec![
+                ("SNAP", "/snap/code/1"),
+                ("SNAP_NAME", "code"),
+                ("CODE", "2"),
+                ("TERMINAL", "3"),
+            ],
+            vec![
+                ("SNAP", "/snap/foo/5"),
+                ("SNAP_NAME", "foo"),
+                ("CODE", "2"),
+                ("TERMINAL", "3"),
+                ("FOO", "4"),
+            ],
+            vec![
+                ("SNAP", "/snap/foo/5"),
+                ("SNAP_NAME", "foo"),
+                ("CODE", "2"),
+                ("TERMINAL", "3"),
+                ("FOO", "4"),
+            ],
+        ])
+    }
+
+    #[test]
+    fn escapes_all_nested_snaps() {
+        let envs = All::detect(
+            Box::new(mock_nested_snaps()),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_maps_to(&envs.external, "OUTSIDE", Some("1"));
+        assert_maps_to(&envs.snap, "CODE", Some("2"));
+        let locations = envs.get_snap_locations();
+        assert!(locations.contains(OsStr::new("/snap/code/1/bin")));
+        assert!(locations.contains(OsStr::new("/snap/foo/5/bin")));
+    }
+
+    #[test]
+    fn escapes_innermost_nested_snap() {
+        let envs = All::detect(
+            Box::new(mock_nested_snaps()),
+            Box::new(sandbox::Snap),
+            Nesting::Innermost,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        assert_maps_to(&envs.external, "TERMINAL", Some("3"));
+        assert_maps_to(&envs.external, "SNAP", Some("/snap/code/1"));
+        assert_maps_to(&envs.snap, "FOO", Some("4"));
+        let locations = envs.get_snap_locations();
+        assert!(!locations.contains(OsStr::new("/snap/code/1/bin")));
+        assert!(locations.contains(OsStr::new("/snap/foo/5/bin")));
+    }
+
+    #[test]
+    fn records_history_of_changes() {
+        let process = process::mock::MockProcess::new(vec![
+            vec![("USER", "alice"), ("FOO", "1")],
+            vec![("USER", "alice"), ("SNAP", "/snap"), ("FOO", "2")],
+            vec![("USER", "alice"), ("SNAP", "/snap"), ("FOO", "2")],
+            vec![("USER", "alice"), ("SNAP", "/snap")],
+        ]);
+        let envs = All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &no_rebuild,
+        )
+        .expect("Failed to detect environments");
+        let history = envs.get_history(OsStr::new("FOO"));
+        let summary: Vec<_> = history
+            .iter()
+            .map(|change| (change.process.pid, change.value.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (100, Some(OsString::from("1"))),
+                (101, Some(OsString::from("2"))),
+                (103, None),
+            ]
+        );
+        assert!(!history[0].process.is_inside());
+        assert!(history[1].process.is_inside());
+        assert_eq!(envs.get_history(OsStr::new("USER")).len(), 1);
+    }
+
     impl All {
         fn mock(
             external: Vec<(&str, &str)>,
@@ -169,9 +935,13 @@ mod tests {
                 map
             }
             All {
+                source: Source::Ancestor,
                 external: Rc::new(vec2map(external)),
                 snap: Rc::new(vec2map(snap)),
                 myself: Rc::new(vec2map(myself)),
+                chain: Vec::new(),
+                sandbox: Box::new(sandbox::Snap),
+                warnings: Vec::new(),
             }
         }
     }
@@ -0,0 +1,267 @@
+use super::environments;
+use super::manager;
+use super::variable::Variable;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::rc::Rc;
+
+const HEADERS: [&str; 6] = ["VARIABLE", "EXTERNAL", "SNAP", "OWN", "RESULT", "REASON"];
+const UNSET: &str = "<unset>";
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+fn display(value: Option<&OsStr>) -> String {
+    match value {
+        Some(value) => value.to_string_lossy().into_owned(),
+        None => String::from(UNSET),
+    }
+}
+
+/// Builds a table of every variable, what values it had and what the child process will get
+/// If color is true, ANSI escape codes are used to highlight the variables that change
+pub fn get_report(manager: &mut manager::Manager, color: bool) -> Result<String, Rc<dyn Error>> {
+    let environments = manager.get_environments_lazy()?;
+    let variables = manager.get_variables_lazy()?;
+    Ok(format_report(&environments, &variables, color))
+}
+
+fn format_report(
+    environments: &environments::All,
+    variables: &HashMap<OsString, Variable>,
+    color: bool,
+) -> String {
+    let locations = environments.get_snap_locations();
+    let mut names: Vec<_> = variables.keys().collect();
+    names.sort();
+    let mut rows = Vec::new();
+    let mut notes = String::new();
+    for name in names {
+        let variable = &variables[name];
+        let result = variable.get_result(&locations);
+        let changed = result.as_deref() != variable.get_myself();
+        let style = match (changed, &result) {
+            (false, _) => DIM,
+            (true, None) => RED,
+            (true, Some(_)) => GREEN,
+        };
+        let cells = [
+            name.to_string_lossy().into_owned(),
+            display(variable.get_external()),
+            display(variable.get_snap()),
+            display(variable.get_myself()),
+            display(result.as_deref()),
+            String::from(variable.get_reason(&locations)),
+        ];
+        if let (true, Some(known)) = (changed, environments.lookup(name)) {
+            writeln!(&mut notes, "  {}: {}", known.name, known.description).unwrap();
+        }
+        rows.push((style, cells));
+    }
+    let mut widths = HEADERS.map(|header| header.chars().count());
+    for (_, cells) in &rows {
+        for (width, cell) in widths.iter_mut().zip(cells.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let boundary = environments.get_boundary();
+    let mut report = format!(
+        "Entered the {} at pid {} ({}), detected by its {}\n\n",
+        environments.get_sandbox_name(),
+        boundary.pid,
+        boundary.comm,
+        boundary
+            .inside
+            .map_or(String::new(), |signal| signal.to_string()),
+    );
+    let headers = HEADERS.map(String::from);
+    for (style, cells) in std::iter::once((BOLD, &headers)).chain(rows.iter().map(|(s, c)| (*s, c)))
+    {
+        let mut line = String::new();
+        for (i, cell) in cells.iter().enumerate() {
+            if i + 1 == cells.len() {
+                line.push_str(cell);
+            } else {
+                write!(&mut line, "{:<width$}  ", cell, width = widths[i]).unwrap();
+            }
+        }
+        if color {
+            writeln!(&mut report, "{}{}{}", style, line, RESET).unwrap();
+        } else {
+            writeln!(&mut report, "{}", line).unwrap();
+        }
+    }
+    if !notes.is_empty() {
+        write!(
+            &mut report,
+            "\nKnown variables that were changed:\n{}",
+            notes
+        )
+        .unwrap();
+    }
+    report
+}
+
+/// Describes every change made to a variable between the process that launched the snap and
+///   this one, along with the process that made it
+pub fn get_blame(manager: &mut manager::Manager, name: &str) -> Result<String, Rc<dyn Error>> {
+    let environments = manager.get_environments_lazy()?;
+    let variables = manager.get_variables_lazy()?;
+    Ok(format_blame(&environments, &variables, name))
+}
+
+fn format_blame(
+    environments: &environments::All,
+    variables: &HashMap<OsString, Variable>,
+    name: &str,
+) -> String {
+    let locations = environments.get_snap_locations();
+    let sandbox = environments.get_sandbox_name();
+    let variable = match variables.get(OsStr::new(name)) {
+        Some(variable) => variable,
+        None => return format!("{} is not set in any process\n", name),
+    };
+    let mut report = format!("{}\n", name);
+    for change in variable.get_history() {
+        let process = &change.process;
+        let exe = match &process.exe {
+            Some(exe) => exe.to_string_lossy().into_owned(),
+            None => String::from("unknown executable"),
+        };
+        let location = if process.snap_owned {
+            format!("owned by the {}", sandbox)
+        } else if let Some(signal) = process.inside {
+            format!("inside the {} (detected by its {})", sandbox, signal)
+        } else {
+            format!("outside the {}", sandbox)
+        };
+        writeln!(
+            &mut report,
+            "  pid {} {} ({}), {}\n    {}",
+            process.pid,
+            process.comm,
+            exe,
+            location,
+            display(change.value.as_deref()),
+        )
+        .unwrap();
+    }
+    writeln!(
+        &mut report,
+        "Result: {} ({})",
+        display(variable.get_result(&locations).as_deref()),
+        variable.get_reason(&locations),
+    )
+    .unwrap();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::environments::{All, Nesting};
+    use super::super::{process, sandbox};
+    use super::*;
+
+    fn detect() -> All {
+        let process = process::mock::MockProcess::with_cmdlines(vec![
+            (
+                vec!["/usr/bin/bash"],
+                vec![("PATH", "/usr/bin"), ("LANG", "C")],
+            ),
+            (
+                vec!["/snap/foo/1/bin/foo"],
+                vec![
+                    ("PATH", "/snap/foo/1/bin:/usr/bin"),
+                    ("LANG", "C"),
+                    ("SNAP", "/snap/foo/1"),
+                    ("SNAP_NAME", "foo"),
+                ],
+            ),
+            (
+                vec!["/usr/bin/sh"],
+                vec![
+                    ("PATH", "/opt/bin:/snap/foo/1/bin:/usr/bin"),
+                    ("LANG", "C"),
+                    ("SNAP", "/snap/foo/1"),
+                    ("SNAP_NAME", "foo"),
+                ],
+            ),
+        ]);
+        All::detect(
+            Box::new(process),
+            Box::new(sandbox::Snap),
+            Nesting::All,
+            &|_| None,
+        )
+        .expect("Failed to detect environments")
+    }
+
+    #[test]
+    fn reports_every_variable() {
+        let environments = detect();
+        let report = format_report(&environments, &environments.consolidate(), false);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Entered the snap at pid 101 (mock), detected by its environment"
+        );
+        let row = |name: &str| {
+            lines
+                .iter()
+                .find(|line| line.starts_with(&format!("{} ", name)))
+                .map(|line| line.split_whitespace().collect::<Vec<_>>())
+                .unwrap_or_else(|| panic!("No row for {}", name))
+        };
+        assert!(lines[2].starts_with("VARIABLE"));
+        assert_eq!(row("LANG"), vec!["LANG", "C", "C", "C", "C", "unchanged"]);
+        assert_eq!(
+            row("PATH")[..5],
+            [
+                "PATH",
+                "/usr/bin",
+                "/snap/foo/1/bin:/usr/bin",
+                "/opt/bin:/snap/foo/1/bin:/usr/bin",
+                "/opt/bin:/usr/bin",
+            ]
+        );
+        assert_eq!(
+            row("SNAP")[..5],
+            ["SNAP", UNSET, "/snap/foo/1", "/snap/foo/1", UNSET]
+        );
+        assert!(report.contains("\nKnown variables that were changed:\n"));
+        assert!(report.contains("\n  SNAP_NAME: Name of the snap, set by snapd\n"));
+        assert!(!report.contains("\n  LANG: "));
+        let colored = format_report(&environments, &environments.consolidate(), true);
+        assert!(colored.contains(&format!("{}SNAP ", RED)));
+        assert!(colored.contains(&format!("{}PATH ", GREEN)));
+        assert!(colored.contains(&format!("{}LANG ", DIM)));
+    }
+
+    #[test]
+    fn blames_processes_that_changed_variable() {
+        let environments = detect();
+        let blame = format_blame(&environments, &environments.consolidate(), "PATH");
+        assert_eq!(
+            blame.lines().collect::<Vec<_>>(),
+            vec![
+                "PATH",
+                "  pid 100 mock (/usr/bin/bash), outside the snap",
+                "    /usr/bin",
+                "  pid 101 mock (/snap/foo/1/bin/foo), owned by the snap",
+                "    /snap/foo/1/bin:/usr/bin",
+                "  pid 102 mock (/usr/bin/sh), inside the snap (detected by its environment)",
+                "    /opt/bin:/snap/foo/1/bin:/usr/bin",
+                "Result: /opt/bin:/usr/bin (changed after snap, snap entries removed)",
+            ]
+        );
+        assert_eq!(
+            format_blame(&environments, &environments.consolidate(), "MISSING"),
+            "MISSING is not set in any process\n"
+        );
+    }
+}
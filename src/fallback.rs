@@ -0,0 +1,757 @@
+use super::environments::Snapshot;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub const ENVIRONMENT_PATH: &str = "/etc/environment";
+pub const PAM_ENV_PATH: &str = "/etc/security/pam_env.conf";
+/// Relative to the user's real home directory
+pub const USER_PAM_ENV_PATH: &str = ".pam_environment";
+/// Where snapd writes the units of snap services
+pub const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+pub const USER_UNIT_DIR: &str = "/etc/systemd/user";
+/// The PATH systemd gives units that don't set their own
+pub const DEFAULT_UNIT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// Where systemd reads the locale it passes on to units, in order of preference
+pub const LOCALE_PATHS: [&str; 2] = ["/etc/locale.conf", "/etc/default/locale"];
+/// How long the user's login shell gets to print its environment before it is killed
+pub const LOGIN_SHELL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the environment of the process that launched the snap came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// Read from the closest ancestor outside of the snap
+    Ancestor,
+    /// Read from the environment of the systemd --user manager process
+    SystemdManager,
+    /// Parsed from the output of systemctl --user show-environment
+    SystemdShowEnvironment,
+    /// Built from /etc/environment and pam_env files
+    EnvironmentFiles,
+    /// Printed by the user's login shell
+    LoginShell,
+    /// Built from the systemd unit of a snap service, on top of what systemd gives every unit
+    ServiceUnit,
+    /// Read from the environment of PID 1, which system services are started from
+    InitProcess,
+    /// Saved by snap-out --record when the snap started
+    Recorded,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::Ancestor => "ancestor process",
+            Source::SystemdManager => "systemd user manager",
+            Source::SystemdShowEnvironment => "systemctl show-environment",
+            Source::EnvironmentFiles => "environment files",
+            Source::LoginShell => "login shell",
+            Source::ServiceUnit => "service unit",
+            Source::InitProcess => "init process",
+            Source::Recorded => "recording",
+        })
+    }
+}
+
+/// Rebuilds the external environment when no ancestor outside of the snap could be found, given
+///   the outermost process inside the snap
+pub type Rebuild<'a> = &'a dyn Fn(&Snapshot) -> Option<(Source, HashMap<OsString, OsString>)>;
+
+/// Tries each source in order, returning the first that produces an environment
+/// Snap services are started by systemd rather than a process we can read, so their unit (and for
+///   system services, PID 1) are tried first
+pub fn rebuild(process: &Snapshot) -> Option<(Source, HashMap<OsString, OsString>)> {
+    let env = &*process.env;
+    let home = get_real_home(env);
+    let service = find_service_unit(&process.cgroups);
+    let mut sources = Vec::new();
+    if let Some(service) = &service {
+        sources.push(Source::ServiceUnit);
+        if !service.user {
+            sources.push(Source::InitProcess);
+        }
+    }
+    sources.extend([
+        Source::SystemdManager,
+        Source::SystemdShowEnvironment,
+        Source::EnvironmentFiles,
+        Source::LoginShell,
+    ]);
+    sources.into_iter().find_map(|source| {
+        let result = match source {
+            Source::Ancestor | Source::Recorded => None,
+            Source::SystemdManager => from_systemd_manager(env),
+            Source::SystemdShowEnvironment => from_show_environment(),
+            Source::EnvironmentFiles => from_environment_files(home.as_deref(), env),
+            Source::LoginShell => from_login_shell(home.as_deref(), env),
+            Source::ServiceUnit => from_service_unit(service.as_ref()?, env),
+            Source::InitProcess => procfs::Process::new(1).ok()?.environ().ok(),
+        };
+        result
+            .filter(|result| !result.is_empty())
+            .map(|result| (source, result))
+    })
+}
+
+/// A systemd service snapd generated for one of a snap's daemons
+#[derive(Debug, PartialEq)]
+struct ServiceUnit {
+    /// The unit's file name, in the form snap.<name>.<app>.service
+    name: String,
+    /// If it is run by the user's systemd instance instead of the system one
+    user: bool,
+}
+
+impl ServiceUnit {
+    fn get_path(&self) -> PathBuf {
+        let dir = if self.user {
+            USER_UNIT_DIR
+        } else {
+            SYSTEM_UNIT_DIR
+        };
+        Path::new(dir).join(&self.name)
+    }
+}
+
+/// Finds the snap service a process runs in from its control groups (apps run in snap.*.scope
+///   groups instead)
+fn find_service_unit(cgroups: &[String]) -> Option<ServiceUnit> {
+    cgroups.iter().find_map(|cgroup| {
+        let name = cgroup
+            .split('/')
+            .find(|name| name.starts_with("snap.") && name.ends_with(".service"))?;
+        Some(ServiceUnit {
+            name: String::from(name),
+            user: cgroup
+                .split('/')
+                .any(|name| name.starts_with("user@") && name.ends_with(".service")),
+        })
+    })
+}
+
+/// Builds the environment systemd starts a service with: its manager's defaults (PATH and the
+///   locale for system services), then the unit's Environment= and EnvironmentFile= settings,
+///   including those in drop-in files
+fn from_service_unit(
+    service: &ServiceUnit,
+    env: &HashMap<OsString, OsString>,
+) -> Option<HashMap<OsString, OsString>> {
+    let path = service.get_path();
+    let mut texts = vec![std::fs::read_to_string(&path).ok()?];
+    let mut drop_ins: Vec<PathBuf> = match std::fs::read_dir(path.with_extension("service.d")) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension() == Some(OsStr::new("conf")))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    drop_ins.sort();
+    texts.extend(
+        drop_ins
+            .iter()
+            .filter_map(|path| std::fs::read_to_string(path).ok()),
+    );
+    let mut result = if service.user {
+        from_systemd_manager(env).unwrap_or_default()
+    } else {
+        HashMap::new()
+    };
+    if !result.contains_key(OsStr::new("PATH")) {
+        result.insert(OsString::from("PATH"), OsString::from(DEFAULT_UNIT_PATH));
+    }
+    if !service.user {
+        if let Some(text) = LOCALE_PATHS
+            .iter()
+            .find_map(|path| std::fs::read_to_string(path).ok())
+        {
+            parse_environment_file(&text, &mut result);
+        }
+    }
+    let mut assigned = HashMap::new();
+    let mut env_files = Vec::new();
+    for text in &texts {
+        parse_unit(text, &mut assigned, &mut env_files);
+    }
+    result.extend(assigned);
+    // systemd applies EnvironmentFile= after Environment=, so files take precedence
+    for (path, required) in env_files {
+        match std::fs::read_to_string(path) {
+            Ok(text) => parse_environment_file(&text, &mut result),
+            Err(_) if required => return None,
+            Err(_) => (),
+        }
+    }
+    Some(result)
+}
+
+/// Adds the Environment= lines in the [Service] section of a unit to assigned, and the paths of
+///   its EnvironmentFile= lines to env_files, each paired with if it must exist (paths starting
+///   with - are optional)
+/// Like systemd, an empty Environment= or EnvironmentFile= drops everything set by earlier ones,
+///   including those in files read before
+fn parse_unit(
+    text: &str,
+    assigned: &mut HashMap<OsString, OsString>,
+    env_files: &mut Vec<(String, bool)>,
+) {
+    let mut in_service = false;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_service = line == "[Service]";
+            continue;
+        }
+        if !in_service || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "Environment" if value.is_empty() => assigned.clear(),
+            "Environment" => {
+                for assignment in split_pam_line(value) {
+                    if let Some((name, value)) = assignment.split_once('=') {
+                        if is_valid_name(name) {
+                            assigned.insert(OsString::from(name), OsString::from(value));
+                        }
+                    }
+                }
+            }
+            "EnvironmentFile" if value.is_empty() => env_files.clear(),
+            "EnvironmentFile" => match value.strip_prefix('-') {
+                Some(path) => env_files.push((String::from(path), false)),
+                None => env_files.push((String::from(value), true)),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Snaps change HOME to a per-snap directory, but keep the real one in SNAP_REAL_HOME
+fn get_real_home(env: &HashMap<OsString, OsString>) -> Option<PathBuf> {
+    env.get(OsStr::new("SNAP_REAL_HOME"))
+        .or_else(|| env.get(OsStr::new("HOME")))
+        .map(PathBuf::from)
+}
+
+/// Reads the environ of the systemd --user manager, found from MANAGERPID (set on processes it
+///   starts) or by looking for it among our user's processes
+fn from_systemd_manager(env: &HashMap<OsString, OsString>) -> Option<HashMap<OsString, OsString>> {
+    let uid = procfs::Process::myself().ok()?.owner;
+    let is_manager = |process: &procfs::Process| {
+        process.owner == uid
+            && process.stat.comm == "systemd"
+            && process
+                .cmdline()
+                .is_ok_and(|cmdline| cmdline.iter().any(|arg| arg == "--user"))
+    };
+    let from_pid = env
+        .get(OsStr::new("MANAGERPID"))
+        .and_then(|pid| pid.to_str()?.parse().ok())
+        .and_then(|pid| procfs::Process::new(pid).ok())
+        .filter(is_manager);
+    let manager = match from_pid {
+        Some(manager) => manager,
+        None => procfs::all_processes().into_iter().find(is_manager)?,
+    };
+    manager.environ().ok()
+}
+
+fn from_show_environment() -> Option<HashMap<OsString, OsString>> {
+    let output = Command::new("systemctl")
+        .args(["--user", "show-environment"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(parse_show_environment(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+fn from_environment_files(
+    home: Option<&Path>,
+    env: &HashMap<OsString, OsString>,
+) -> Option<HashMap<OsString, OsString>> {
+    let shell = env.get(OsStr::new("SHELL")).map(|s| s.to_string_lossy());
+    let home_str = home.map(|home| home.to_string_lossy());
+    let mut result = HashMap::new();
+    if let Ok(text) = std::fs::read_to_string(ENVIRONMENT_PATH) {
+        parse_environment_file(&text, &mut result);
+    }
+    let mut pam_paths = vec![PathBuf::from(PAM_ENV_PATH)];
+    if let Some(home) = home {
+        pam_paths.push(home.join(USER_PAM_ENV_PATH));
+    }
+    for path in pam_paths {
+        if let Ok(text) = std::fs::read_to_string(path) {
+            parse_pam_env(
+                &text,
+                &mut result,
+                home_str.as_deref().unwrap_or(""),
+                shell.as_deref().unwrap_or(""),
+            );
+        }
+    }
+    Some(result)
+}
+
+/// Runs the user's shell as a login shell with an almost empty environment, and records what it
+///   sets up
+fn from_login_shell(
+    home: Option<&Path>,
+    env: &HashMap<OsString, OsString>,
+) -> Option<HashMap<OsString, OsString>> {
+    let shell = env.get(OsStr::new("SHELL"))?;
+    let mut command = Command::new(shell);
+    command
+        .args(["-l", "-c", "env -0"])
+        .env_clear()
+        .stdin(Stdio::null())
+        .stderr(Stdio::null());
+    for name in ["USER", "LOGNAME", "SHELL"] {
+        if let Some(value) = env.get(OsStr::new(name)) {
+            command.env(name, value);
+        }
+    }
+    if let Some(home) = home {
+        command.env("HOME", home).current_dir(home);
+    }
+    let output = run_with_timeout(command, LOGIN_SHELL_TIMEOUT)?;
+    Some(parse_nul_separated(&output))
+}
+
+/// Runs a command and returns its output if it succeeds within the timeout
+/// Otherwise the command is killed, along with anything it started (such as a login script waiting
+///   for input)
+fn run_with_timeout(mut command: Command, timeout: Duration) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut child = command
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .ok()?;
+    let mut stdout = child.stdout.take()?;
+    // Read on another thread, since whatever holds the pipe open may never exit
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = sender.send(stdout.read_to_end(&mut output).map(|_| output));
+    });
+    let output = receiver.recv_timeout(timeout).ok().and_then(Result::ok);
+    let status = loop {
+        match child.try_wait() {
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            Ok(status) => break status,
+            Err(_) => break None,
+        }
+    };
+    match (output, status) {
+        (Some(output), Some(status)) if status.success() => Some(output),
+        (_, Some(_)) => None,
+        (_, None) => {
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            let _ = child.wait();
+            None
+        }
+    }
+}
+
+/// Parses NAME=VALUE entries separated by NUL bytes, as printed by env -0
+pub fn parse_nul_separated(bytes: &[u8]) -> HashMap<OsString, OsString> {
+    bytes
+        .split(|b| *b == 0)
+        .filter_map(|entry| {
+            let split = entry.iter().position(|b| *b == b'=')?;
+            Some((
+                OsStr::from_bytes(&entry[..split]).to_owned(),
+                OsStr::from_bytes(&entry[split + 1..]).to_owned(),
+            ))
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// Parses NAME=VALUE lines, where newer versions of systemd write values with special characters
+///   as $'...' with C-style escapes
+fn parse_show_environment(text: &str) -> HashMap<OsString, OsString> {
+    let mut result = HashMap::new();
+    for line in text.lines() {
+        if let Some((name, value)) = line.split_once('=') {
+            let value = match value.strip_prefix("$'").and_then(|v| v.strip_suffix('\'')) {
+                Some(quoted) => unescape(quoted),
+                None => String::from(value),
+            };
+            result.insert(OsString::from(name), OsString::from(value));
+        }
+    }
+    result
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+/// Parses /etc/environment, which has NAME=VALUE lines (optionally prefixed with export) and
+///   does no expansion
+fn parse_environment_file(text: &str, result: &mut HashMap<OsString, OsString>) {
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        if let Some((name, value)) = line.split_once('=') {
+            if is_valid_name(name) {
+                result.insert(OsString::from(name), OsString::from(unquote(value.trim())));
+            }
+        }
+    }
+}
+
+/// Splits a pam_env line on whitespace, except inside quotes
+fn split_pam_line(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, c) if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Expands ${NAME} from the variables set so far, and @{HOME} and @{SHELL} from the user
+fn expand_pam_value(
+    value: &str,
+    result: &HashMap<OsString, OsString>,
+    home: &str,
+    shell: &str,
+) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find(['$', '@', '\\']) {
+        expanded.push_str(&rest[..start]);
+        let marker = &rest[start..];
+        if let Some(escaped) = marker.strip_prefix('\\') {
+            let mut chars = escaped.chars();
+            if let Some(c) = chars.next() {
+                expanded.push(c);
+            }
+            rest = chars.as_str();
+            continue;
+        }
+        let end = match (marker.get(1..2), marker.find('}')) {
+            (Some("{"), Some(end)) => end,
+            _ => {
+                expanded.push_str(&marker[..1]);
+                rest = &marker[1..];
+                continue;
+            }
+        };
+        let name = &marker[2..end];
+        match (&marker[..1], name) {
+            ("@", "HOME") => expanded.push_str(home),
+            ("@", "SHELL") => expanded.push_str(shell),
+            ("$", _) => {
+                if let Some(value) = result.get(OsStr::new(name)) {
+                    expanded.push_str(&value.to_string_lossy());
+                }
+            }
+            _ => (),
+        }
+        rest = &marker[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Parses a pam_env.conf style file, where each line is NAME [DEFAULT=value] [OVERRIDE=value]
+/// A non-empty OVERRIDE wins over DEFAULT, and if both are empty the variable is removed
+fn parse_pam_env(text: &str, result: &mut HashMap<OsString, OsString>, home: &str, shell: &str) {
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = split_pam_line(line);
+        // A line of nothing but quotes has no words at all
+        let name = match words.first() {
+            Some(name) => name,
+            None => continue,
+        };
+        if words.len() == 1 {
+            // Older ~/.pam_environment files may use NAME=VALUE lines
+            if let Some((name, value)) = name.split_once('=') {
+                if is_valid_name(name) {
+                    result.insert(OsString::from(name), OsString::from(value));
+                }
+            }
+            continue;
+        }
+        if !is_valid_name(name) {
+            continue;
+        }
+        let mut default = String::new();
+        let mut override_value = String::new();
+        for word in &words[1..] {
+            if let Some(value) = word.strip_prefix("DEFAULT=") {
+                default = expand_pam_value(value, result, home, shell);
+            } else if let Some(value) = word.strip_prefix("OVERRIDE=") {
+                override_value = expand_pam_value(value, result, home, shell);
+            }
+        }
+        let value = if override_value.is_empty() {
+            default
+        } else {
+            override_value
+        };
+        if value.is_empty() {
+            result.remove(OsStr::new(name));
+        } else {
+            result.insert(OsString::from(name), OsString::from(value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get<'a>(map: &'a HashMap<OsString, OsString>, name: &str) -> Option<&'a str> {
+        map.get(OsStr::new(name)).and_then(|value| value.to_str())
+    }
+
+    #[test]
+    fn kills_commands_that_time_out() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo started; sleep 30"]);
+        let start = Instant::now();
+        assert_eq!(run_with_timeout(command, Duration::from_millis(100)), None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo done"]);
+        assert_eq!(
+            run_with_timeout(command, Duration::from_secs(10)),
+            Some(b"done\n".to_vec())
+        );
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo failed; exit 1"]);
+        assert_eq!(run_with_timeout(command, Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn parses_nul_separated_env() {
+        let env = parse_nul_separated(b"A=1\0B=x=y\0\0C=\0");
+        assert_eq!(env.len(), 3);
+        assert_eq!(get(&env, "A"), Some("1"));
+        assert_eq!(get(&env, "B"), Some("x=y"));
+        assert_eq!(get(&env, "C"), Some(""));
+    }
+
+    #[test]
+    fn parses_show_environment() {
+        let env = parse_show_environment("LANG=en_US.UTF-8\nPS1=$'a\\tb\\'c'\nPATH=/usr/bin\n");
+        assert_eq!(get(&env, "LANG"), Some("en_US.UTF-8"));
+        assert_eq!(get(&env, "PS1"), Some("a\tb'c"));
+        assert_eq!(get(&env, "PATH"), Some("/usr/bin"));
+    }
+
+    #[test]
+    fn parses_etc_environment() {
+        let mut env = HashMap::new();
+        parse_environment_file(
+            "# comment\nPATH=\"/usr/local/bin:/usr/bin\"\nexport EDITOR=vim\nnot valid=1\n",
+            &mut env,
+        );
+        assert_eq!(env.len(), 2);
+        assert_eq!(get(&env, "PATH"), Some("/usr/local/bin:/usr/bin"));
+        assert_eq!(get(&env, "EDITOR"), Some("vim"));
+    }
+
+    #[test]
+    fn parses_pam_env() {
+        let mut env = HashMap::new();
+        env.insert(OsString::from("PATH"), OsString::from("/usr/bin"));
+        env.insert(OsString::from("REMOVED"), OsString::from("1"));
+        parse_pam_env(
+            "# comment
+BIN DEFAULT=@{HOME}/bin
+PATH DEFAULT=${PATH}:${BIN} OVERRIDE=
+MANPATH DEFAULT=/usr/man OVERRIDE=\"/opt/my man\"
+ESCAPED DEFAULT=\\${PATH}
+REMOVED DEFAULT=
+\"\"
+''
+OLD_STYLE=1
+",
+            &mut env,
+            "/home/alice",
+            "/bin/bash",
+        );
+        assert_eq!(get(&env, "BIN"), Some("/home/alice/bin"));
+        assert_eq!(get(&env, "PATH"), Some("/usr/bin:/home/alice/bin"));
+        assert_eq!(get(&env, "MANPATH"), Some("/opt/my man"));
+        assert_eq!(get(&env, "ESCAPED"), Some("${PATH}"));
+        assert_eq!(get(&env, "REMOVED"), None);
+        assert_eq!(get(&env, "OLD_STYLE"), Some("1"));
+    }
+
+    #[test]
+    fn finds_service_units() {
+        assert_eq!(
+            find_service_unit(&[String::from("/system.slice/snap.foo.daemon.service")]),
+            Some(ServiceUnit {
+                name: String::from("snap.foo.daemon.service"),
+                user: false,
+            })
+        );
+        let user = find_service_unit(&[String::from(
+            "/user.slice/user-1000.slice/user@1000.service/app.slice/snap.foo.agent.service",
+        )])
+        .expect("Failed to find user service");
+        assert!(user.user);
+        assert_eq!(
+            user.get_path(),
+            Path::new("/etc/systemd/user/snap.foo.agent.service")
+        );
+        assert_eq!(
+            find_service_unit(&[String::from(
+                "/user.slice/user-1000.slice/user@1000.service/app.slice/snap.foo.foo-1.scope"
+            )]),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_unit_environment() {
+        let mut env = HashMap::new();
+        let mut files = Vec::new();
+        parse_unit(
+            "[Unit]
+Environment=IGNORED=1
+[Service]
+# Environment=COMMENTED=1
+Environment=A=1 \"B=two words\"
+Environment=C=3
+EnvironmentFile=/etc/foo.env
+EnvironmentFile=-/etc/optional.env
+ExecStart=/usr/bin/snap run foo.daemon
+[Install]
+WantedBy=multi-user.target
+",
+            &mut env,
+            &mut files,
+        );
+        assert_eq!(env.len(), 3);
+        assert_eq!(get(&env, "A"), Some("1"));
+        assert_eq!(get(&env, "B"), Some("two words"));
+        assert_eq!(get(&env, "C"), Some("3"));
+        assert_eq!(
+            files,
+            vec![
+                (String::from("/etc/foo.env"), true),
+                (String::from("/etc/optional.env"), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_unit_settings_reset_earlier_ones() {
+        let mut env = HashMap::new();
+        let mut files = Vec::new();
+        parse_unit(
+            "[Service]
+Environment=A=1 B=2
+EnvironmentFile=/etc/foo.env
+",
+            &mut env,
+            &mut files,
+        );
+        // As in a drop-in file
+        parse_unit(
+            "[Service]
+Environment=
+Environment=C=3
+EnvironmentFile=
+",
+            &mut env,
+            &mut files,
+        );
+        assert_eq!(env.len(), 1);
+        assert_eq!(get(&env, "C"), Some("3"));
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn uses_snap_real_home() {
+        let mut env = HashMap::new();
+        env.insert(
+            OsString::from("HOME"),
+            OsString::from("/home/alice/snap/foo/1"),
+        );
+        assert_eq!(
+            get_real_home(&env),
+            Some(PathBuf::from("/home/alice/snap/foo/1"))
+        );
+        env.insert(
+            OsString::from("SNAP_REAL_HOME"),
+            OsString::from("/home/alice"),
+        );
+        assert_eq!(get_real_home(&env), Some(PathBuf::from("/home/alice")));
+    }
+}
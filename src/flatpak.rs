@@ -0,0 +1,310 @@
+use super::command;
+use super::fallback;
+use super::rules::{self, Rules};
+use super::sandbox::{Flatpak, Sandbox};
+use super::variable::{self, Variable};
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+/// Program used to run commands on the host
+/// It goes through the org.freedesktop.Flatpak.Development.HostCommand D-Bus method, which
+///   requires the app to have --talk-name=org.freedesktop.Flatpak
+pub const SPAWN_PROGRAM: &str = "flatpak-spawn";
+
+/// Variables to set (or clear, when None) for the command run on the host
+type Changes = Vec<(OsString, Option<OsString>)>;
+
+/// File flatpak creates at the root of every sandbox
+const INFO_PATH: &str = "/.flatpak-info";
+
+/// Returns if we are running inside a Flatpak sandbox
+pub fn is_inside(env: &HashMap<OsString, OsString>) -> bool {
+    Path::new(INFO_PATH).exists() || Flatpak.is_inside(env)
+}
+
+/// Reads the environment commands started on the host get, by running env on the host
+pub fn get_host_env(spawn_program: &str) -> Result<HashMap<OsString, OsString>, Box<dyn Error>> {
+    let output = std::process::Command::new(spawn_program)
+        .args(["--host", "env", "-0"])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("Could not run {}: {}", spawn_program, e))?;
+    if !output.status.success() {
+        bail!(
+            "Could not read the host's environment: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(fallback::parse_nul_separated(&output.stdout))
+}
+
+/// Returns the changes to make to the host's environment for a command run on the host
+/// Every variable goes through the same decisions as inside a snap, with the host's environment
+///   as the external one. What the sandbox started with can't be seen, so it is treated as unset:
+///   variables missing inside the sandbox keep their host value, lists are merged with the host's
+///   and anything else set inside the sandbox is kept unless it points into it.
+pub fn get_host_vars(
+    env: &HashMap<OsString, OsString>,
+    host_env: &HashMap<OsString, OsString>,
+    rules: &Rules,
+) -> Changes {
+    let locations = Flatpak.get_locations(env);
+    let mut names: Vec<&OsString> = env.keys().chain(host_env.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let host_value = host_env.get(name);
+            let mut variable = Variable::new(host_value.cloned(), None, env.get(name).cloned())
+                .with_known(variable::lookup(name, Flatpak.get_catalog()));
+            variable.set_policy(rules.get_policy(name));
+            let value = variable.get_result(&locations);
+            if value.as_ref() == host_value {
+                None
+            } else {
+                Some((name.clone(), value))
+            }
+        })
+        .collect()
+}
+
+/// Works out the changes to make to the host's environment, applying the config files
+pub fn get_vars(
+    spawn_program: &str,
+    env: &HashMap<OsString, OsString>,
+) -> Result<Changes, Box<dyn Error>> {
+    let host_env = get_host_env(spawn_program)?;
+    let paths = rules::config_paths(Flatpak.get_root(env).as_deref(), &host_env);
+    let rules = Rules::load(&paths, Flatpak.get_app_name(env).as_deref());
+    for warning in rules.get_warnings() {
+        eprintln!("{}: warning: {}", env!("CARGO_PKG_NAME"), warning);
+    }
+    Ok(get_host_vars(env, &host_env, &rules))
+}
+
+/// Writes the variables to set into an anonymous file, in the format of env -0, so they can be
+///   passed to the spawn program without showing up in its command line
+fn write_env_file(vars: &[(OsString, Option<OsString>)]) -> std::io::Result<File> {
+    let fd = unsafe { libc::memfd_create(b"snap-out-env\0".as_ptr() as *const libc::c_char, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    for (name, value) in vars {
+        if let Some(value) = value {
+            file.write_all(name.as_bytes())?;
+            file.write_all(b"=")?;
+            file.write_all(value.as_bytes())?;
+            file.write_all(b"\0")?;
+        }
+    }
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Returns the arguments for the spawn program that run the command on the host, reading the
+///   variables to set from env_fd
+fn get_spawn_args(
+    command: &str,
+    args: &[String],
+    vars: &[(OsString, Option<OsString>)],
+    env_fd: RawFd,
+    directory: Option<&Path>,
+) -> Vec<OsString> {
+    let mut spawn_args = vec![OsString::from("--host")];
+    if let Some(directory) = directory {
+        let mut arg = OsString::from("--directory=");
+        arg.push(directory);
+        spawn_args.push(arg);
+    }
+    spawn_args.push(OsString::from(format!("--env-fd={}", env_fd)));
+    for (name, _) in vars.iter().filter(|(_, value)| value.is_none()) {
+        let mut arg = OsString::from("--unset-env=");
+        arg.push(name);
+        spawn_args.push(arg);
+    }
+    spawn_args.push(OsString::from(command));
+    spawn_args.extend(args.iter().map(OsString::from));
+    spawn_args
+}
+
+/// Runs a command on the host through the given spawn program (normally SPAWN_PROGRAM) and
+///   returns its exit code (in exec mode the spawn program replaces this process)
+/// The command gets the current working directory and the changes from get_vars()
+pub fn run(
+    mode: &command::Mode,
+    spawn_program: &str,
+    command: &str,
+    args: &[String],
+    vars: &[(OsString, Option<OsString>)],
+) -> i32 {
+    let env_file = match write_env_file(vars) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!(
+                "{}: Could not pass on the environment: {}",
+                env!("CARGO_PKG_NAME"),
+                e
+            );
+            return command::EXIT_INTERNAL_ERROR;
+        }
+    };
+    let env_fd = env_file.as_raw_fd();
+    let directory = std::env::current_dir().ok();
+    let mut spawn = std::process::Command::new(spawn_program);
+    spawn.args(get_spawn_args(
+        command,
+        args,
+        vars,
+        env_fd,
+        directory.as_deref(),
+    ));
+    command::run_command(mode, spawn, &[env_fd])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::process::mock::mock_env;
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Stands in for flatpak-spawn by running the command locally with the given directory and
+    ///   variables, or printing the environment when asked for it
+    const STAND_IN_SCRIPT: &str = r#"#!/bin/sh
+[ "$1" = "--host" ] || exit 99
+shift
+while true; do
+    case "$1" in
+        --directory=*) cd "${1#--directory=}" || exit 98 ;;
+        --env-fd=*)
+            while IFS= read -r var; do
+                export "$var"
+            done <<EOF
+$(tr '\0' '\n' <&"${1#--env-fd=}")
+EOF
+            ;;
+        --unset-env=*) unset "${1#--unset-env=}" ;;
+        *) break ;;
+    esac
+    shift
+done
+exec "$@"
+"#;
+
+    fn write_stand_in() -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "snap-out-test-flatpak-spawn-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, STAND_IN_SCRIPT).expect("Failed to write stand-in script");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("Failed to make stand-in script executable");
+        path
+    }
+
+    #[test]
+    fn merges_sandbox_variables_into_host_vars() {
+        let env = mock_env(vec![
+            ("FLATPAK_ID", "org.example.Foo"),
+            (
+                "XDG_CONFIG_HOME",
+                "/home/alice/.var/app/org.example.Foo/config",
+            ),
+            ("PATH", "/home/alice/bin:/app/bin:/usr/bin"),
+            ("LD_LIBRARY_PATH", "/app/lib"),
+            ("GIT_DIR", "/app/share/repo"),
+            ("DISPLAY", ":0"),
+            ("EDITOR", "vim"),
+            ("LANG", "C"),
+        ]);
+        let host_env = mock_env(vec![
+            ("PATH", "/usr/local/bin:/usr/bin:/bin"),
+            ("DISPLAY", ":0"),
+            ("LANG", "en_US.UTF-8"),
+            ("SSH_AUTH_SOCK", "/run/user/1000/ssh"),
+        ]);
+        let mut rules = Rules::default();
+        rules.parse("LANG = restore\n", None);
+        assert_eq!(
+            get_host_vars(&env, &host_env, &rules),
+            vec![
+                (OsString::from("EDITOR"), Some(OsString::from("vim"))),
+                (
+                    OsString::from("PATH"),
+                    Some(OsString::from(
+                        "/home/alice/bin:/usr/local/bin:/usr/bin:/bin"
+                    ))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_spawn_args() {
+        assert_eq!(
+            get_spawn_args(
+                "ls",
+                &[String::from("-a")],
+                &[
+                    (OsString::from("FOO"), Some(OsString::from("secret"))),
+                    (OsString::from("BAR"), None)
+                ],
+                5,
+                Some(Path::new("/home/alice")),
+            ),
+            vec![
+                "--host",
+                "--directory=/home/alice",
+                "--env-fd=5",
+                "--unset-env=BAR",
+                "ls",
+                "-a"
+            ]
+            .into_iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reads_host_env() {
+        let path = write_stand_in();
+        let host_env = get_host_env(path.to_str().unwrap());
+        std::fs::remove_file(&path).expect("Failed to remove stand-in script");
+        let host_env = host_env.expect("Failed to read host environment");
+        assert_eq!(
+            host_env.get(OsStr::new("PATH")),
+            std::env::var_os("PATH").as_ref()
+        );
+    }
+
+    #[test]
+    fn runs_through_spawn_program() {
+        let path = write_stand_in();
+        let cwd = std::env::current_dir().expect("Failed to get current directory");
+        let vars = vec![
+            (OsString::from("FOO"), Some(OsString::from("a b=c"))),
+            (OsString::from("EXPECTED_DIR"), Some(cwd.into_os_string())),
+            (OsString::from("HOME"), None),
+        ];
+        let script =
+            r#"[ "$FOO" = "a b=c" ] && [ -z "$HOME" ] && [ "$(pwd)" = "$EXPECTED_DIR" ] && exit 7"#;
+        let exit_code = run(
+            &command::Mode::Spawn,
+            path.to_str().unwrap(),
+            "sh",
+            &[String::from("-c"), String::from(script)],
+            &vars,
+        );
+        std::fs::remove_file(&path).expect("Failed to remove stand-in script");
+        assert_eq!(exit_code, 7);
+    }
+}
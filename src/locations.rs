@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Variables the snap sets that point to directories belonging to it
+const LOCATION_VARS: &[&str] = &[
+    "SNAP",
+    "SNAP_DATA",
+    "SNAP_USER_DATA",
+    "SNAP_COMMON",
+    "SNAP_USER_COMMON",
+];
+
+/// The directories that belong to the snap (or other sandbox)
+/// Anything pointing inside one of these should not leak out to an external process
+#[derive(Debug, Default, PartialEq)]
+pub struct Locations {
+    prefixes: Vec<PathBuf>,
+}
+
+/// Returns if the final component of a path is a snap revision (such as 42, x1 or current)
+fn is_revision(component: &OsStr, revision: Option<&OsString>) -> bool {
+    if Some(component) == revision.map(OsString::as_os_str) || component == "current" {
+        return true;
+    }
+    match component.to_str() {
+        Some(s) => {
+            let digits = s.strip_prefix('x').unwrap_or(s);
+            !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+impl Locations {
+    /// Detects the snap's locations from an environment inside the snap
+    /// Revisioned directories are widened to cover every revision, so paths from before a
+    ///   refresh are still recognized
+    pub fn from_snap_env(env: &HashMap<OsString, OsString>) -> Self {
+        let revision = env.get(OsStr::new("SNAP_REVISION"));
+        let mut locations = Locations::default();
+        for var in LOCATION_VARS {
+            let path = match env.get(OsStr::new(var)) {
+                Some(value) => Path::new(value),
+                None => continue,
+            };
+            let path = match (path.file_name(), path.parent()) {
+                (Some(name), Some(parent)) if is_revision(name, revision) => parent,
+                _ => path,
+            };
+            locations.add(path);
+        }
+        locations
+    }
+
+    /// Creates locations from a list of directories
+    pub fn from_dirs<'a>(dirs: impl IntoIterator<Item = &'a Path>) -> Self {
+        let mut locations = Locations::default();
+        for dir in dirs {
+            locations.add(dir);
+        }
+        locations
+    }
+
+    /// Adds every directory of other to this
+    pub fn merge(&mut self, other: Locations) {
+        for prefix in other.prefixes {
+            self.add(&prefix);
+        }
+    }
+
+    fn add(&mut self, path: &Path) {
+        // Never treat the root, or a relative path, as belonging to the sandbox
+        if !path.is_absolute() || path.parent().is_none() {
+            return;
+        }
+        if !self.prefixes.iter().any(|p| path.starts_with(p)) {
+            self.prefixes.retain(|p| !p.starts_with(path));
+            self.prefixes.push(path.to_path_buf());
+        }
+    }
+
+    /// Returns if the given path is inside the sandbox
+    pub fn contains(&self, path: &OsStr) -> bool {
+        let path = Path::new(path);
+        self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::process::mock::mock_env;
+    use super::*;
+
+    fn mock_locations() -> Locations {
+        Locations::from_snap_env(&mock_env(vec![
+            ("SNAP", "/snap/foo/12"),
+            ("SNAP_REVISION", "12"),
+            ("SNAP_DATA", "/var/snap/foo/12"),
+            ("SNAP_COMMON", "/var/snap/foo/common"),
+            ("SNAP_USER_DATA", "/home/alice/snap/foo/12"),
+            ("SNAP_USER_COMMON", "/home/alice/snap/foo/common"),
+        ]))
+    }
+
+    #[test]
+    fn contains_paths_in_current_revision() {
+        let locations = mock_locations();
+        assert!(locations.contains(OsStr::new("/snap/foo/12/usr/bin")));
+        assert!(locations.contains(OsStr::new("/var/snap/foo/12")));
+        assert!(locations.contains(OsStr::new("/home/alice/snap/foo/common/cache")));
+    }
+
+    #[test]
+    fn contains_paths_in_other_revisions() {
+        let locations = mock_locations();
+        assert!(locations.contains(OsStr::new("/snap/foo/11/usr/lib")));
+        assert!(locations.contains(OsStr::new("/snap/foo/current/usr/lib")));
+        assert!(locations.contains(OsStr::new("/var/snap/foo/x3")));
+    }
+
+    #[test]
+    fn does_not_contain_external_paths() {
+        let locations = mock_locations();
+        assert!(!locations.contains(OsStr::new("/usr/bin")));
+        assert!(!locations.contains(OsStr::new("/snap/bin")));
+        assert!(!locations.contains(OsStr::new("/snap/foobar/12")));
+        assert!(!locations.contains(OsStr::new("/home/alice/snap/bar/1")));
+        assert!(!locations.contains(OsStr::new("relative/path")));
+    }
+
+    #[test]
+    fn contains_paths_in_dirs() {
+        let locations = Locations::from_dirs(vec![Path::new("/tmp/.mount_fooAbC"), Path::new("/")]);
+        assert!(locations.contains(OsStr::new("/tmp/.mount_fooAbC/usr/lib")));
+        assert!(!locations.contains(OsStr::new("/tmp/.mount_fooXyZ/usr/lib")));
+        assert!(!locations.contains(OsStr::new("/usr/lib")));
+    }
+
+    #[test]
+    fn merges_locations() {
+        let mut locations = Locations::from_dirs(vec![Path::new("/snap/foo/1")]);
+        locations.merge(Locations::from_dirs(vec![
+            Path::new("/snap/foo"),
+            Path::new("/snap/bar/2"),
+        ]));
+        assert_eq!(
+            locations,
+            Locations::from_dirs(vec![Path::new("/snap/foo"), Path::new("/snap/bar/2")])
+        );
+    }
+
+    #[test]
+    fn ignores_root_locations() {
+        let locations =
+            Locations::from_snap_env(&mock_env(vec![("SNAP", "/1"), ("SNAP_DATA", "")]));
+        assert!(!locations.contains(OsStr::new("/usr/bin")));
+    }
+}
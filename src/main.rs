@@ -1,23 +1,49 @@
 #[macro_use]
 extern crate simple_error;
 
+mod cache;
 mod command;
 mod debug;
+mod desktop;
+mod detach;
 mod environments;
+mod explain;
+mod fallback;
+mod flatpak;
+mod locations;
 mod manager;
+mod open;
 mod options;
 mod process;
+mod recipe;
+mod record;
+mod rules;
+mod sandbox;
+mod spawn;
+mod terminal;
 mod variable;
 
+use std::collections::HashMap;
 use std::ffi::OsString;
+use std::io::IsTerminal;
 
 fn get_help_text() -> String {
-    return format!(
-        "Usage: {pkg} [COMMAND] [ARGUMENTS]...
+    format!(
+        "Usage: {pkg} [RUN OPTION]... [COMMAND] [ARGUMENTS]...
        {pkg} [OPTION]
 
 {desc}
 
+Run options:
+  --spawn           Run COMMAND as a child process and wait for it, instead of replacing
+                    {pkg} with it (so it keeps the same PID)
+                    Signals and control of the terminal are passed on to COMMAND
+  --detach          Start COMMAND in a session of its own and exit without waiting for it, so
+                    it keeps running after whatever started {pkg} exits
+  --log FILE        With --detach, append the output of COMMAND to FILE instead of discarding it
+  --pid-file FILE   With --detach, write the PID of COMMAND to FILE
+  --argv0 NAME      Run COMMAND with NAME as its argv[0]
+
 Options:
   -h, --help        Print this help message and exit
   -v, --version     Print the version and exit
@@ -25,32 +51,130 @@ Options:
                     Output consists of lines in the following two formats:
                       export VARIABLE=VALUE
                       unset VARIABLE
+  -e, --explain     Print a table of every variable, the values it held and why it was changed
+                    Output is colored when writing to a terminal, follow with --plain to disable
+  -b, --blame VAR   Show every process between the snap's launcher and this one that changed VAR
+  --recipe          Print a script that exports {recipe_var}, a signed description of the
+                    changes to make, for the snap app to eval at startup
+                    Later runs from its children apply it directly instead of detecting again
+  --open FILE|URI...
+                    Open each file or URI with the host's default handler (xdg-open or
+                    gio open), detached in a session of its own; exits with the handler's
+                    status if it fails within a couple of seconds, otherwise with 0
+  --desktop-file ID|PATH [FILE|URI]...
+                    Launch an application of the host from its desktop entry, found in the
+                    applications directory of XDG_DATA_HOME or XDG_DATA_DIRS, detached as with
+                    --detach, passing it any files or URIs given
+  --terminal [-- COMMAND [ARGUMENTS]...]
+                    Open the host's terminal emulator in the current directory, detached as
+                    with --detach, running COMMAND in it if given
+  -r, --record [COMMAND] [ARGUMENTS]...
+                    Save the environment the snap was launched with, then run COMMAND unchanged
+                    Meant to be the first entry of the snap's command-chain, so later runs can
+                    use it even once the launcher is no longer an ancestor
+
+Exit status:
+  When running a command, its exit status, or one of:
+  {signal_status:<17} COMMAND was killed by signal N (if {pkg} can't be killed the same way)
+  {not_found:<17} COMMAND was not found
+  {not_executable:<17} COMMAND was found but could not be executed
+  {internal:<17} {pkg} itself failed
+  {missing_file:<17} A file passed to --open does not exist
 
 Environment variables:
   {debug_var:<17} If set, dump debugging information to {debug_path}
+  {opener_var:<17} Command to open files and URIs with when the host has neither xdg-open
+                    nor gio
+  {terminal_var:<17} Terminal emulator for --terminal and desktop entries that need one,
+                    used before $TERMINAL, x-terminal-emulator, gnome-terminal, konsole and xterm
+  {nesting_var:<17} When launched from inside another snap, set to innermost to only escape
+                    the innermost snap, or to all (the default) to escape every snap
+
+Config files:
+  Per-variable rules are read from {system_config}, $SNAP/{snap_config}
+  ($APPDIR/{snap_config} in an AppImage) and ~/.config/{user_config},
+  with later files taking precedence
+  Each line is a rule in the form PATTERN = POLICY, where PATTERN is a variable name that may
+  include * and ? wildcards, and POLICY is one of:
+    auto      Undo the changes made by the snap (the default)
+    keep      Leave the variable as-is
+    unset     Always clear the variable
+    restore   Always use the value from outside the snap
+  Rules under a [SNAP_NAME] header only apply to that snap (or AppImage of that file name),
+  and the last matching rule wins
 ",
         pkg = env!("CARGO_PKG_NAME"),
         desc = env!("CARGO_PKG_DESCRIPTION"),
+        signal_status = format!("{}+N", command::EXIT_SIGNAL_BASE),
+        not_found = command::EXIT_NOT_FOUND,
+        not_executable = command::EXIT_NOT_EXECUTABLE,
+        internal = command::EXIT_INTERNAL_ERROR,
+        missing_file = open::EXIT_MISSING_FILE,
         debug_var = debug::DEBUG_ENV_VAR,
         debug_path = debug::DEBUG_DUMP_PATH,
-    );
+        nesting_var = environments::NESTING_ENV_VAR,
+        opener_var = open::OPENER_ENV_VAR,
+        terminal_var = terminal::TERMINAL_ENV_VAR,
+        recipe_var = recipe::RECIPE_ENV_VAR,
+        system_config = rules::SYSTEM_CONFIG_PATH,
+        snap_config = rules::SNAP_CONFIG_PATH,
+        user_config = rules::USER_CONFIG_PATH,
+    )
+}
+
+/// Works out the changes to make to the host's environment when running from inside a Flatpak,
+///   falling back to no changes
+fn get_flatpak_vars(env: &HashMap<OsString, OsString>) -> Vec<(OsString, Option<OsString>)> {
+    flatpak::get_vars(flatpak::SPAWN_PROGRAM, env).unwrap_or_else(|e| {
+        eprintln!(
+            "{}: {}, running in unmodified environment",
+            env!("CARGO_PKG_NAME"),
+            e
+        );
+        Vec::new()
+    })
 }
 
 fn main() {
     let parsed = options::parse(std::env::args());
     let mut manager = manager::Manager::new(parsed);
     let mut exit_code = 0;
+    let env: HashMap<OsString, OsString> = std::env::vars_os().collect();
     match &*manager.get_options() {
-        options::RunCommand { command, args } => {
-            exit_code = match manager.get_variables_to_change_lazy() {
-                Ok(vars) => command::run(command, args, &*vars),
+        options::RunCommand {
+            command,
+            args,
+            argv0,
+            mode,
+        } if flatpak::is_inside(&env) => {
+            if argv0.is_some() {
+                eprintln!(
+                    "{}: --argv0 is not supported inside a Flatpak sandbox, ignoring it",
+                    env!("CARGO_PKG_NAME")
+                );
+            }
+            let vars = get_flatpak_vars(&env);
+            debug::dump_info_if_needed(&mut manager);
+            exit_code = flatpak::run(mode, flatpak::SPAWN_PROGRAM, command, args, &vars);
+        }
+        options::RunCommand {
+            command,
+            args,
+            argv0,
+            mode,
+        } => {
+            let vars = manager.get_variables_to_change_lazy();
+            // In exec mode nothing after the command runs, so the dump has to be written first
+            debug::dump_info_if_needed(&mut manager);
+            exit_code = match vars {
+                Ok(vars) => command::run(mode, command, argv0.as_deref(), args, &*vars),
                 Err(e) => {
                     eprintln!(
                         "{}: {}, running in unmodified environment",
                         env!("CARGO_PKG_NAME"),
                         e
                     );
-                    command::run(command, args, command::NO_VARS)
+                    command::run(mode, command, argv0.as_deref(), args, command::NO_VARS)
                 }
             }
         }
@@ -60,18 +184,162 @@ fn main() {
         options::ShowVersion => {
             println!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
         }
+        options::ShowScript | options::ShowExplanation { .. } | options::ShowBlame { .. }
+            if flatpak::is_inside(&env) =>
+        {
+            eprintln!(
+                "{}: --script, --explain and --blame are not supported inside a Flatpak sandbox",
+                env!("CARGO_PKG_NAME")
+            );
+            exit_code = command::EXIT_INTERNAL_ERROR;
+        }
         options::ShowScript => {
             match manager.get_setup_script_lazy() {
                 Ok(script) => println!("{}", &*script),
-                Err(e) => eprintln!("{}: {}", env!("CARGO_PKG_NAME"), e),
+                Err(e) => {
+                    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), e);
+                    exit_code = command::EXIT_INTERNAL_ERROR;
+                }
+            };
+        }
+        options::ShowExplanation { plain } => {
+            let color = !*plain
+                && std::io::stdout().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none();
+            match explain::get_report(&mut manager, color) {
+                Ok(report) => print!("{}", report),
+                Err(e) => {
+                    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), e);
+                    exit_code = command::EXIT_INTERNAL_ERROR;
+                }
+            };
+        }
+        options::ShowBlame { variable } => {
+            match explain::get_blame(&mut manager, variable) {
+                Ok(report) => print!("{}", report),
+                Err(e) => {
+                    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), e);
+                    exit_code = command::EXIT_INTERNAL_ERROR;
+                }
+            };
+        }
+        options::Open { targets } if flatpak::is_inside(&env) => {
+            let vars = get_flatpak_vars(&env);
+            let (targets, missing) = open::get_handler_targets(targets);
+            exit_code = missing;
+            for target in targets {
+                let result = flatpak::run(
+                    &command::Mode::Session,
+                    flatpak::SPAWN_PROGRAM,
+                    "xdg-open",
+                    &[target.to_string_lossy().into_owned()],
+                    &vars,
+                );
+                if result != 0 {
+                    exit_code = result;
+                }
+            }
+        }
+        options::Open { targets } => {
+            exit_code = match manager.get_variables_to_change_lazy() {
+                Ok(vars) => open::run(targets, &vars, &env),
+                Err(e) => {
+                    eprintln!(
+                        "{}: {}, running in unmodified environment",
+                        env!("CARGO_PKG_NAME"),
+                        e
+                    );
+                    open::run(targets, &[], &env)
+                }
+            }
+        }
+        options::LaunchDesktopFile { .. } if flatpak::is_inside(&env) => {
+            eprintln!(
+                "{}: --desktop-file is not supported inside a Flatpak sandbox",
+                env!("CARGO_PKG_NAME")
+            );
+            exit_code = command::EXIT_INTERNAL_ERROR;
+        }
+        options::LaunchDesktopFile { id, targets } => {
+            exit_code = match manager.get_variables_to_change_lazy() {
+                Ok(vars) => desktop::run(id, targets, &vars, &env),
+                Err(e) => {
+                    eprintln!(
+                        "{}: {}, running in unmodified environment",
+                        env!("CARGO_PKG_NAME"),
+                        e
+                    );
+                    desktop::run(id, targets, &[], &env)
+                }
+            }
+        }
+        options::OpenTerminal { command } if flatpak::is_inside(&env) => {
+            // The host's PATH can't be searched from inside the sandbox, so the configured
+            //   emulator is used as-is
+            let name = terminal::get_configured(&env)
+                .unwrap_or_else(|| OsString::from(terminal::FALLBACKS[0]));
+            let directory = std::env::current_dir().ok();
+            let mut args = vec![name.to_string_lossy().into_owned()];
+            args.extend(
+                terminal::get_args(name.as_ref(), directory.as_deref(), command)
+                    .into_iter()
+                    .map(|arg| arg.to_string_lossy().into_owned()),
+            );
+            let mode = command::Mode::Detach {
+                log: None,
+                pid_file: None,
+            };
+            let vars = get_flatpak_vars(&env);
+            exit_code = flatpak::run(&mode, flatpak::SPAWN_PROGRAM, &args[0], &args[1..], &vars);
+        }
+        options::OpenTerminal { command } => {
+            exit_code = match manager.get_variables_to_change_lazy() {
+                Ok(vars) => terminal::run(command, &vars, &env),
+                Err(e) => {
+                    eprintln!(
+                        "{}: {}, running in unmodified environment",
+                        env!("CARGO_PKG_NAME"),
+                        e
+                    );
+                    terminal::run(command, &[], &env)
+                }
+            }
+        }
+        options::ShowRecipe => {
+            match manager.get_recipe() {
+                Ok(recipe) => println!("export {}={}", recipe::RECIPE_ENV_VAR, recipe),
+                Err(e) => {
+                    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), e);
+                    exit_code = command::EXIT_INTERNAL_ERROR;
+                }
             };
         }
+        options::Record { command, args } => {
+            if let Err(e) = manager.record() {
+                eprintln!(
+                    "{}: Could not record the environment: {}",
+                    env!("CARGO_PKG_NAME"),
+                    e
+                );
+            }
+            if let Some(command) = command {
+                debug::dump_info_if_needed(&mut manager);
+                exit_code =
+                    command::run(&command::Mode::Exec, command, None, args, command::NO_VARS);
+            }
+        }
         options::Error(e) => {
             eprintln!("Error parsing arguments: {}", e);
-            exit_code = 1;
+            exit_code = command::EXIT_INTERNAL_ERROR;
         }
         options::None => eprintln!("No command to run, use --help for help"),
     }
-    debug::dump_info_if_needed(&mut manager);
+    match &*manager.get_options() {
+        options::RunCommand { .. }
+        | options::Record {
+            command: Some(_), ..
+        } => (),
+        _ => debug::dump_info_if_needed(&mut manager),
+    }
     std::process::exit(exit_code);
 }
@@ -1,6 +1,7 @@
 use super::*;
 use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::OsStr;
 use std::rc::Rc;
 
 type GenericResult<T> = Result<Rc<T>, Rc<dyn Error>>;
@@ -9,6 +10,7 @@ type CachedResult<T> = Option<GenericResult<T>>;
 pub struct Manager {
     options: Rc<options::Parsed>,
     environments: CachedResult<environments::All>,
+    rules: CachedResult<rules::Rules>,
     variables: CachedResult<HashMap<OsString, variable::Variable>>,
     variables_to_change: CachedResult<Vec<(OsString, Option<OsString>)>>,
     setup_script: CachedResult<String>,
@@ -19,6 +21,7 @@ impl Manager {
         Manager {
             options: Rc::new(options),
             environments: None,
+            rules: None,
             variables: None,
             variables_to_change: None,
             setup_script: None,
@@ -27,23 +30,310 @@ impl Manager {
 
     fn init_environments(&self) -> GenericResult<environments::All> {
         let process = process::ProcfsProcess::myself()?;
-        let environments = environments::All::detect(Box::new(process))?;
+        let snapshot = environments::Snapshot::from_process(&process);
+        let sandbox = match sandbox::detect(&snapshot) {
+            Some(sandbox) => sandbox,
+            None => {
+                return Err(Rc::new(simple_error::SimpleError::new(
+                    "Not inside a snap or AppImage",
+                )))
+            }
+        };
+        // When recording, the environment has to come from the ancestors
+        let recording = matches!(&*self.options, options::Record { .. });
+        if !recording {
+            if let Some(recorded) = Self::find_recording(&snapshot, &*sandbox) {
+                let environments = environments::All::from_recording(
+                    recorded.launch,
+                    snapshot,
+                    sandbox,
+                    recorded.env,
+                )?;
+                return Ok(Rc::new(environments));
+            }
+        }
+        let environments = environments::All::detect(
+            Box::new(process),
+            sandbox,
+            environments::Nesting::from_env()?,
+            &fallback::rebuild,
+        )?;
+        for warning in environments.get_warnings() {
+            eprintln!("{}: warning: {}", env!("CARGO_PKG_NAME"), warning);
+        }
         Ok(Rc::new(environments))
     }
 
+    fn find_recording(
+        snapshot: &environments::Snapshot,
+        sandbox: &dyn sandbox::Sandbox,
+    ) -> Option<record::Recording> {
+        let dir = record::get_dir(
+            snapshot
+                .env
+                .get(OsStr::new("XDG_RUNTIME_DIR"))
+                .map(|d| d.as_os_str()),
+        )?;
+        match record::find(&dir, &sandbox.get_instance_name(&snapshot.env)?, snapshot) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                eprintln!("{}: warning: {}", env!("CARGO_PKG_NAME"), e);
+                None
+            }
+        }
+    }
+
+    /// Saves the environment of the process that launched the snap for the rest of this launch,
+    ///   and returns where it was saved
+    /// The current process goes on to run the rest of the command chain, so the recording is
+    ///   keyed to it.
+    pub fn record(&mut self) -> Result<std::path::PathBuf, Rc<dyn Error>> {
+        let environments = self.get_environments_lazy()?;
+        let dir = record::get_dir(std::env::var_os("XDG_RUNTIME_DIR").as_deref());
+        let (dir, instance) = match (dir, environments.get_instance_name()) {
+            (Some(dir), Some(instance)) => (dir, instance),
+            _ => {
+                return Err(Rc::new(simple_error::SimpleError::new(
+                    "XDG_RUNTIME_DIR or the instance name is not set",
+                )))
+            }
+        };
+        let myself = environments.get_chain().last().unwrap();
+        record::save(&dir, &instance, myself, environments.get_external())
+            .map_err(|e| Rc::new(simple_error::SimpleError::new(e.to_string())) as Rc<dyn Error>)
+    }
+
+    /// Reads the decisions an earlier run passed down through RECIPE_ENV_VAR, checking they were
+    ///   made for this snap instance, and returns the changes they make to our environment
+    /// Returns None if a variable the recipe decided on has changed since, as what to do with it
+    ///   then needs to be detected
+    fn read_recipe(recipe: &str) -> Result<Option<recipe::Changes>, Box<dyn Error>> {
+        let process = process::ProcfsProcess::myself()?;
+        let snapshot = environments::Snapshot::from_process(&process);
+        let sandbox = match sandbox::detect(&snapshot) {
+            Some(sandbox) => sandbox,
+            None => bail!("Not inside a snap with XDG_RUNTIME_DIR set"),
+        };
+        let instance = sandbox.get_instance_name(&snapshot.env);
+        let runtime_dir = snapshot.env.get(OsStr::new("XDG_RUNTIME_DIR"));
+        let decisions = match (
+            instance,
+            recipe::get_key_path(runtime_dir.map(|d| d.as_os_str())),
+        ) {
+            (Some(instance), Some(key_path)) => {
+                recipe::decode(recipe, &instance, &recipe::load_key(&key_path)?)?
+            }
+            _ => bail!("Not inside a snap with XDG_RUNTIME_DIR set"),
+        };
+        Ok(Self::apply_decisions(&decisions, &snapshot, &*sandbox))
+    }
+
+    /// Looks for changes cached for this launch by an earlier run, before looking at any ancestor
+    /// Returns None if there are none, or if they were decided for a different environment
+    fn read_cache() -> Result<Option<recipe::Changes>, Box<dyn Error>> {
+        let process = process::ProcfsProcess::myself()?;
+        let snapshot = environments::Snapshot::from_process(&process);
+        let sandbox = match sandbox::detect(&snapshot) {
+            Some(sandbox) => sandbox,
+            None => return Ok(None),
+        };
+        let dir = cache::get_dir(
+            snapshot
+                .env
+                .get(OsStr::new("XDG_RUNTIME_DIR"))
+                .map(|d| d.as_os_str()),
+        );
+        // An invalid nesting mode is reported by the detection that follows
+        let nesting = environments::Nesting::from_env().ok();
+        let (dir, instance, nesting) =
+            match (dir, sandbox.get_instance_name(&snapshot.env), nesting) {
+                (Some(dir), Some(instance), Some(nesting)) => (dir, instance, nesting),
+                _ => return Ok(None),
+            };
+        let header = cache::get_header(sandbox.get_name(), &instance, nesting);
+        Ok(cache::find(&dir, &header, &snapshot)?
+            .and_then(|decisions| Self::apply_decisions(&decisions, &snapshot, &*sandbox)))
+    }
+
+    /// Caches detected changes under the boundary, so later runs in the same launch skip detection
+    /// The cache only saves time, so failing to write it isn't worth reporting
+    fn save_cache(&mut self, changes: &[(OsString, Option<OsString>)]) {
+        let environments = match self.get_environments_lazy() {
+            Ok(environments) => environments,
+            Err(_) => return,
+        };
+        // Results where the current process is the boundary can't be reused
+        if environments.get_chain().len() <= 2 {
+            return;
+        }
+        let dir = cache::get_dir(std::env::var_os("XDG_RUNTIME_DIR").as_deref());
+        let nesting = environments::Nesting::from_env().ok();
+        let (dir, instance, nesting) = match (dir, environments.get_instance_name(), nesting) {
+            (Some(dir), Some(instance), Some(nesting)) => (dir, instance, nesting),
+            _ => return,
+        };
+        let header = cache::get_header(environments.get_sandbox().get_name(), &instance, nesting);
+        let rules = rules::config_paths(
+            environments.get_sandbox_root().as_deref(),
+            environments.get_external(),
+        );
+        let decisions = Self::get_decisions(&environments, changes);
+        let _ = cache::save(
+            &dir,
+            environments.get_boundary(),
+            &header,
+            &rules,
+            &decisions,
+        );
+    }
+
+    /// Applies decisions made by an earlier run to the current environment, see recipe::apply()
+    fn apply_decisions(
+        decisions: &[recipe::Decision],
+        snapshot: &environments::Snapshot,
+        sandbox: &dyn sandbox::Sandbox,
+    ) -> Option<recipe::Changes> {
+        let locations = sandbox.get_locations(&snapshot.env);
+        recipe::apply(decisions, &snapshot.env, |name, value| {
+            variable::points_into(
+                value,
+                variable::lookup(name, sandbox.get_catalog()),
+                &locations,
+            )
+        })
+    }
+
+    /// Ties each change to the value it was decided from, so runs that see a different value don't
+    ///   apply it
+    /// Values pointing into the snap that are left as they are (such as by a keep rule) are
+    ///   decided on too, since runs applying the decisions detect again if they find one that isn't
+    fn get_decisions(
+        environments: &environments::All,
+        changes: &[(OsString, Option<OsString>)],
+    ) -> Vec<recipe::Decision> {
+        let mut decisions: Vec<_> = changes
+            .iter()
+            .filter(|(name, _)| name != recipe::RECIPE_ENV_VAR)
+            .map(|(name, value)| recipe::Decision {
+                name: name.clone(),
+                from: std::env::var_os(name),
+                to: value.clone(),
+            })
+            .collect();
+        let locations = environments.get_snap_locations();
+        let sandbox = environments.get_sandbox();
+        for (name, value) in std::env::vars_os() {
+            let decided = name == recipe::RECIPE_ENV_VAR || changes.iter().any(|(n, _)| *n == name);
+            if !decided
+                && variable::points_into(
+                    &value,
+                    variable::lookup(&name, sandbox.get_catalog()),
+                    &locations,
+                )
+            {
+                decisions.push(recipe::Decision {
+                    name,
+                    from: Some(value.clone()),
+                    to: Some(value),
+                });
+            }
+        }
+        decisions
+    }
+
+    /// Builds a recipe for the changes this run would make, to be exported to the snap app's
+    ///   children so their runs can skip detection
+    pub fn get_recipe(&mut self) -> Result<String, Rc<dyn Error>> {
+        let environments = self.get_environments_lazy()?;
+        let vars = self.get_variables_to_change_lazy()?;
+        let decisions = Self::get_decisions(&environments, &vars);
+        let (instance, key_path) = match (
+            environments.get_instance_name(),
+            recipe::get_key_path(std::env::var_os("XDG_RUNTIME_DIR").as_deref()),
+        ) {
+            (Some(instance), Some(key_path)) => (instance, key_path),
+            _ => {
+                return Err(Rc::new(simple_error::SimpleError::new(
+                    "XDG_RUNTIME_DIR or the instance name is not set",
+                )))
+            }
+        };
+        let key = recipe::load_or_create_key(&key_path)?;
+        Ok(recipe::encode(&decisions, &instance, &key))
+    }
+
+    fn init_rules(&mut self) -> GenericResult<rules::Rules> {
+        let environments = self.get_environments_lazy()?;
+        let app_name = environments.get_app_name();
+        let paths = rules::config_paths(
+            environments.get_sandbox_root().as_deref(),
+            environments.get_external(),
+        );
+        let rules = rules::Rules::load(&paths, app_name.as_deref());
+        for warning in rules.get_warnings() {
+            eprintln!("{}: warning: {}", env!("CARGO_PKG_NAME"), warning);
+        }
+        Ok(Rc::new(rules))
+    }
+
     fn init_variables(&mut self) -> GenericResult<HashMap<OsString, variable::Variable>> {
-        Ok(Rc::new(self.get_environments_lazy()?.consolidate()))
+        let mut variables = self.get_environments_lazy()?.consolidate();
+        let rules = self.get_rules_lazy()?;
+        for (name, variable) in variables.iter_mut() {
+            variable.set_policy(rules.get_policy(name));
+        }
+        Ok(Rc::new(variables))
     }
 
     fn init_variables_to_change(&mut self) -> GenericResult<Vec<(OsString, Option<OsString>)>> {
-        Ok(Rc::new(
-            self.get_variables_lazy()?
-                .iter()
-                .filter_map(|(name, val)| {
-                    val.get_required_change().map(|v| (OsString::from(name), v))
-                })
-                .collect(),
-        ))
+        let recipe = match (&*self.options, std::env::var(recipe::RECIPE_ENV_VAR)) {
+            (options::ShowRecipe, _) | (_, Err(_)) => None,
+            (_, Ok(recipe)) => match Self::read_recipe(&recipe) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    eprintln!(
+                        "{}: warning: Ignoring {}: {}",
+                        env!("CARGO_PKG_NAME"),
+                        recipe::RECIPE_ENV_VAR,
+                        e
+                    );
+                    None
+                }
+            },
+        };
+        let cached = match recipe {
+            Some(changes) => Some(changes),
+            None => Self::read_cache().unwrap_or_else(|e| {
+                eprintln!(
+                    "{}: warning: Ignoring the cache: {}",
+                    env!("CARGO_PKG_NAME"),
+                    e
+                );
+                None
+            }),
+        };
+        let mut changes = match cached {
+            Some(changes) => changes,
+            None => {
+                let locations = self.get_environments_lazy()?.get_snap_locations();
+                let changes: Vec<_> = self
+                    .get_variables_lazy()?
+                    .iter()
+                    .filter_map(|(name, val)| {
+                        val.get_required_change(&locations)
+                            .map(|v| (OsString::from(name), v))
+                    })
+                    .collect();
+                self.save_cache(&changes);
+                changes
+            }
+        };
+        // The recipe only applies inside the snap, so it is never passed outside of it
+        if std::env::var_os(recipe::RECIPE_ENV_VAR).is_some() {
+            changes.retain(|(name, _)| name != recipe::RECIPE_ENV_VAR);
+            changes.push((OsString::from(recipe::RECIPE_ENV_VAR), None));
+        }
+        Ok(Rc::new(changes))
     }
 
     fn init_setup_script(&mut self) -> GenericResult<String> {
@@ -55,10 +345,10 @@ impl Manager {
             match (name.to_str(), value) {
                 (Some(name), Some(value)) =>
                     match value.to_str() {
-                        Some(value) => write!(&mut setters, "export {}={}\n", name, value).unwrap(),
+                        Some(value) => writeln!(&mut setters, "export {}={}", name, value).unwrap(),
                         None => eprintln!("Variable {:?} is not included because it's value {:?} includes invalid unicode", name, value.to_string_lossy()),
                     },
-                (Some(name), None) => write!(&mut unsetters, "unset {}\n", name).unwrap(),
+                (Some(name), None) => writeln!(&mut unsetters, "unset {}", name).unwrap(),
                 (None, _) => eprintln!("Variable {:?} is not included because it's name includes invalid unicode", name.to_string_lossy()),
             }
         }
@@ -70,14 +360,21 @@ impl Manager {
     }
 
     pub fn get_environments_lazy(&mut self) -> GenericResult<environments::All> {
-        if let None = self.environments {
+        if self.environments.is_none() {
             self.environments = Some(self.init_environments());
         }
         self.environments.as_ref().unwrap().clone()
     }
 
+    pub fn get_rules_lazy(&mut self) -> GenericResult<rules::Rules> {
+        if self.rules.is_none() {
+            self.rules = Some(self.init_rules());
+        }
+        self.rules.as_ref().unwrap().clone()
+    }
+
     pub fn get_variables_lazy(&mut self) -> GenericResult<HashMap<OsString, variable::Variable>> {
-        if let None = self.variables {
+        if self.variables.is_none() {
             self.variables = Some(self.init_variables());
         }
         self.variables.as_ref().unwrap().clone()
@@ -86,14 +383,14 @@ impl Manager {
     pub fn get_variables_to_change_lazy(
         &mut self,
     ) -> GenericResult<Vec<(OsString, Option<OsString>)>> {
-        if let None = self.variables_to_change {
+        if self.variables_to_change.is_none() {
             self.variables_to_change = Some(self.init_variables_to_change());
         }
         self.variables_to_change.as_ref().unwrap().clone()
     }
 
     pub fn get_setup_script_lazy(&mut self) -> GenericResult<String> {
-        if let None = self.setup_script {
+        if self.setup_script.is_none() {
             self.setup_script = Some(self.init_setup_script());
         }
         self.setup_script.as_ref().unwrap().clone()
@@ -0,0 +1,267 @@
+use super::command;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the command to open files and URIs with if the host has neither
+///   xdg-open nor gio
+pub const OPENER_ENV_VAR: &str = "SNAP_OUT_OPENER";
+/// Exit code when a file to open doesn't exist (the same as xdg-open's)
+pub const EXIT_MISSING_FILE: i32 = 2;
+/// Handlers that are tried in order, with the arguments that go before the file or URI
+const HANDLERS: [(&str, &[&str]); 2] = [("xdg-open", &[]), ("gio", &["open"])];
+
+/// Returns the scheme of a URI, or None if the text isn't one
+fn get_scheme(text: &str) -> Option<&str> {
+    let (scheme, _) = text.split_once(':')?;
+    let mut chars = scheme.chars();
+    if chars.next()?.is_ascii_alphabetic()
+        && chars.all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+    {
+        Some(scheme)
+    } else {
+        None
+    }
+}
+
+fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    Some(bytes)
+}
+
+/// Returns the local path a file:// URI points to, or None if it is on another host
+fn get_file_uri_path(uri: &str) -> Option<PathBuf> {
+    let rest = &uri[uri.find(':')? + 1..];
+    let path = match rest.strip_prefix("//") {
+        Some(authority_and_path) => {
+            let split = authority_and_path.find('/')?;
+            match &authority_and_path[..split] {
+                "" | "localhost" => &authority_and_path[split..],
+                _ => return None,
+            }
+        }
+        None => rest,
+    };
+    let path = path.split(['?', '#']).next()?;
+    Some(PathBuf::from(OsString::from_vec(percent_decode(path)?)))
+}
+
+/// What to pass to the handler for one of the arguments to --open
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    Path(PathBuf),
+    Uri(String),
+    /// A path (or file URI) to something that doesn't exist
+    Missing(PathBuf),
+}
+
+/// Works out whether the text is a path or a URI
+/// Paths (including local file:// URIs) are made absolute, since the handler may pass them on to
+///   an app that is already running in another directory
+pub fn resolve_target(text: &str, cwd: &Path) -> Target {
+    let path = cwd.join(text);
+    let path = match get_scheme(text) {
+        _ if path.exists() => path,
+        Some(scheme) if scheme.eq_ignore_ascii_case("file") => match get_file_uri_path(text) {
+            Some(path) if path.is_absolute() => path,
+            _ => return Target::Uri(String::from(text)),
+        },
+        Some(_) => return Target::Uri(String::from(text)),
+        None => path,
+    };
+    if path.exists() {
+        Target::Path(path)
+    } else {
+        Target::Missing(path)
+    }
+}
+
+/// Returns if a path is a file with any execute bit set
+pub fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Looks for an executable file with the given name in a PATH-style list of directories
+pub fn find_in_path(name: &str, path: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Picks the command (and arguments before the target) to open files and URIs with
+/// PATH is the cleaned one, so the host's handler is found instead of one shipped in the snap
+pub fn find_handler(
+    path: Option<&OsStr>,
+    fallback: Option<&OsStr>,
+) -> Option<(OsString, Vec<&'static str>)> {
+    let found = path.and_then(|path| {
+        HANDLERS.iter().find_map(|&(name, args)| {
+            find_in_path(name, path).map(|handler| (handler.into_os_string(), args.to_vec()))
+        })
+    });
+    found.or_else(|| {
+        fallback
+            .filter(|fallback| !fallback.is_empty())
+            .map(|fallback| (fallback.to_owned(), Vec::new()))
+    })
+}
+
+/// Returns the value a variable will have once the changes are made
+pub fn get_changed_value(
+    name: &str,
+    vars: &[(OsString, Option<OsString>)],
+    env: &HashMap<OsString, OsString>,
+) -> Option<OsString> {
+    match vars.iter().find(|(var, _)| var == name) {
+        Some((_, value)) => value.clone(),
+        None => env.get(OsStr::new(name)).cloned(),
+    }
+}
+
+/// Works out what to pass to the handler for each target, reporting the ones that don't exist
+/// Returns the targets that can be opened, and the exit code to use because of the ones that
+///   can't (0 if there are none)
+pub fn get_handler_targets(targets: &[String]) -> (Vec<OsString>, i32) {
+    let cwd = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+    let mut exit_code = 0;
+    let mut resolved = Vec::new();
+    for text in targets {
+        match resolve_target(text, &cwd) {
+            Target::Path(path) => resolved.push(path.into_os_string()),
+            Target::Uri(uri) => resolved.push(OsString::from(uri)),
+            Target::Missing(path) => {
+                eprintln!(
+                    "{}: {} does not exist",
+                    env!("CARGO_PKG_NAME"),
+                    path.display()
+                );
+                exit_code = EXIT_MISSING_FILE;
+            }
+        }
+    }
+    (resolved, exit_code)
+}
+
+/// Opens each target with the host's handler, which is detached in a session of its own so
+///   whatever it launches outlives the snap app and doesn't keep this process waiting
+/// Returns the exit code of the last handler that failed within command::SESSION_WAIT, or 0
+pub fn run(
+    targets: &[String],
+    vars: &[(OsString, Option<OsString>)],
+    env: &HashMap<OsString, OsString>,
+) -> i32 {
+    let path = get_changed_value("PATH", vars, env);
+    let fallback = env.get(OsStr::new(OPENER_ENV_VAR));
+    let (handler, handler_args) =
+        match find_handler(path.as_deref(), fallback.map(|f| f.as_os_str())) {
+            Some(handler) => handler,
+            None => {
+                eprintln!(
+                    "{}: Could not find xdg-open or gio, set {} to the command to open files with",
+                    env!("CARGO_PKG_NAME"),
+                    OPENER_ENV_VAR
+                );
+                return command::EXIT_NOT_FOUND;
+            }
+        };
+    let (targets, mut exit_code) = get_handler_targets(targets);
+    for target in targets {
+        let mut args: Vec<OsString> = handler_args.iter().map(OsString::from).collect();
+        args.push(target);
+        let result = command::run(
+            &command::Mode::Session,
+            &handler.to_string_lossy(),
+            None,
+            &args,
+            vars,
+        );
+        if result != 0 {
+            exit_code = result;
+        }
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_uris() {
+        assert_eq!(get_scheme("https://example.com"), Some("https"));
+        assert_eq!(get_scheme("mailto:alice@example.com"), Some("mailto"));
+        assert_eq!(get_scheme("file.txt"), None);
+        assert_eq!(get_scheme("dir/a:b"), None);
+        assert_eq!(get_scheme("1x:y"), None);
+    }
+
+    #[test]
+    fn resolves_targets() {
+        let cwd = std::env::temp_dir();
+        let name = format!("snap-out-open-test {}", std::process::id());
+        let file = cwd.join(&name);
+        assert_eq!(resolve_target(&name, &cwd), Target::Missing(file.clone()));
+        std::fs::write(&file, "").expect("Failed to write file");
+        assert_eq!(resolve_target(&name, &cwd), Target::Path(file.clone()));
+        let uri = format!("file://{}", file.to_str().unwrap().replace(' ', "%20"));
+        assert_eq!(resolve_target(&uri, &cwd), Target::Path(file.clone()));
+        let uri = format!("file://localhost{}", file.to_str().unwrap());
+        assert_eq!(resolve_target(&uri, &cwd), Target::Path(file.clone()));
+        std::fs::remove_file(&file).expect("Failed to clean up");
+        assert_eq!(resolve_target(&uri, &cwd), Target::Missing(file));
+        assert_eq!(
+            resolve_target("https://example.com/a b", &cwd),
+            Target::Uri(String::from("https://example.com/a b"))
+        );
+        assert_eq!(
+            resolve_target("file://server/share/a", &cwd),
+            Target::Uri(String::from("file://server/share/a"))
+        );
+    }
+
+    #[test]
+    fn finds_handler() {
+        let dir = std::env::temp_dir().join(format!("snap-out-open-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let path = OsString::from(format!("/nonexistent:{}", dir.display()));
+        let fallback = OsStr::new("opener");
+        assert_eq!(
+            find_handler(Some(&path), Some(fallback)),
+            Some((OsString::from("opener"), vec![]))
+        );
+        assert_eq!(find_handler(Some(&path), None), None);
+        for name in ["gio", "xdg-open"] {
+            std::fs::write(dir.join(name), "").expect("Failed to write handler");
+        }
+        assert_eq!(find_handler(Some(&path), None), None);
+        for name in ["gio", "xdg-open"] {
+            std::fs::set_permissions(dir.join(name), std::fs::Permissions::from_mode(0o755))
+                .expect("Failed to make handler executable");
+        }
+        assert_eq!(
+            find_handler(Some(&path), Some(fallback)),
+            Some((dir.join("xdg-open").into_os_string(), vec![]))
+        );
+        std::fs::remove_file(dir.join("xdg-open")).expect("Failed to remove handler");
+        assert_eq!(
+            find_handler(Some(&path), Some(fallback)),
+            Some((dir.join("gio").into_os_string(), vec!["open"]))
+        );
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+}
@@ -1,9 +1,37 @@
+use super::command::Mode;
 pub use Parsed::*;
 
 #[derive(Debug, PartialEq)]
 pub enum Parsed {
-    RunCommand { command: String, args: Vec<String> },
+    RunCommand {
+        command: String,
+        args: Vec<String>,
+        /// What the command sees as its own name, if different from the command
+        argv0: Option<String>,
+        mode: Mode,
+    },
     ShowScript,
+    ShowExplanation {
+        plain: bool,
+    },
+    ShowBlame {
+        variable: String,
+    },
+    Record {
+        command: Option<String>,
+        args: Vec<String>,
+    },
+    ShowRecipe,
+    Open {
+        targets: Vec<String>,
+    },
+    LaunchDesktopFile {
+        id: String,
+        targets: Vec<String>,
+    },
+    OpenTerminal {
+        command: Vec<String>,
+    },
     ShowHelp,
     ShowVersion,
     Error(String),
@@ -22,20 +50,120 @@ pub fn parse(args: impl std::iter::Iterator<Item = impl AsRef<str>>) -> Parsed {
                 Parsed::ShowVersion
             } else if command == "--script" || command == "-s" {
                 Parsed::ShowScript
+            } else if command == "--explain" || command == "-e" {
+                match args.next() {
+                    Some(arg) if arg.as_ref() == "--plain" => {
+                        Parsed::ShowExplanation { plain: true }
+                    }
+                    Some(arg) => Parsed::Error(format!("Unknown argument {}", arg.as_ref())),
+                    _ => Parsed::ShowExplanation { plain: false },
+                }
+            } else if command == "--blame" || command == "-b" {
+                match args.next() {
+                    Some(variable) => Parsed::ShowBlame {
+                        variable: String::from(variable.as_ref()),
+                    },
+                    _ => Parsed::Error(format!("{} requires a variable name", command)),
+                }
+            } else if ["--argv0", "--spawn", "--detach", "--log", "--pid-file"].contains(&&*command)
+            {
+                parse_run_command(command, args)
+            } else if command == "--open" {
+                let targets: Vec<String> = args.map(|s| String::from(s.as_ref())).collect();
+                if targets.is_empty() {
+                    Parsed::Error(String::from("--open requires a file or URI"))
+                } else {
+                    Parsed::Open { targets }
+                }
+            } else if command == "--desktop-file" {
+                match args.next() {
+                    Some(id) => Parsed::LaunchDesktopFile {
+                        id: String::from(id.as_ref()),
+                        targets: args.map(|s| String::from(s.as_ref())).collect(),
+                    },
+                    _ => Parsed::Error(String::from("--desktop-file requires an ID or path")),
+                }
+            } else if command == "--terminal" {
+                match args.next() {
+                    Some(arg) if arg.as_ref() == "--" => Parsed::OpenTerminal {
+                        command: args.map(|s| String::from(s.as_ref())).collect(),
+                    },
+                    Some(arg) => Parsed::Error(format!(
+                        "Unknown argument {}, the command must follow --",
+                        arg.as_ref()
+                    )),
+                    _ => Parsed::OpenTerminal { command: vec![] },
+                }
+            } else if command == "--recipe" {
+                Parsed::ShowRecipe
+            } else if command == "--record" || command == "-r" {
+                Parsed::Record {
+                    command: args.next().map(|s| String::from(s.as_ref())),
+                    args: args.map(|s| String::from(s.as_ref())).collect(),
+                }
             } else {
                 Parsed::Error(format!("Unknown argument {}", command))
             }
         } else {
-            Parsed::RunCommand {
-                command,
-                args: args.map(|s| String::from(s.as_ref())).collect(),
-            }
+            parse_run_command(command, args)
         }
     } else {
         Parsed::None
     }
 }
 
+/// Parses options for how to run the command, followed by the command and its arguments
+fn parse_run_command(first: String, mut args: impl Iterator<Item = impl AsRef<str>>) -> Parsed {
+    let mut spawn = false;
+    let mut detach = false;
+    let mut argv0 = Option::None;
+    let mut log = Option::None;
+    let mut pid_file = Option::None;
+    let mut next = Some(first);
+    while let Some(arg) = next {
+        match arg.as_str() {
+            "--spawn" => spawn = true,
+            "--detach" => detach = true,
+            "--argv0" | "--log" | "--pid-file" => match args.next() {
+                Some(value) => {
+                    let value = Some(String::from(value.as_ref()));
+                    match arg.as_str() {
+                        "--argv0" => argv0 = value,
+                        "--log" => log = value,
+                        _ => pid_file = value,
+                    }
+                }
+                _ => return Parsed::Error(format!("{} requires a value", arg)),
+            },
+            _ if arg.starts_with('-') => {
+                return Parsed::Error(format!("Unknown argument {}", arg));
+            }
+            _ => {
+                if !detach && (log.is_some() || pid_file.is_some()) {
+                    return Parsed::Error(String::from("--log and --pid-file require --detach"));
+                }
+                let mode = if spawn && detach {
+                    return Parsed::Error(String::from("--spawn and --detach can't be combined"));
+                } else if spawn {
+                    Mode::Spawn
+                } else if detach {
+                    Mode::Detach { log, pid_file }
+                } else {
+                    Mode::Exec
+                };
+                return Parsed::RunCommand {
+                    command: arg,
+                    args: args.map(|s| String::from(s.as_ref())).collect(),
+                    argv0,
+                    mode,
+                };
+            }
+        }
+        next = args.next().map(|s| String::from(s.as_ref()));
+    }
+    Parsed::Error(String::from("No command to run"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -43,10 +171,12 @@ mod tests {
     #[test]
     fn parses_command_with_no_args() {
         assert_eq!(
-            parse(vec!["snap-out", "ls"].iter()),
+            parse(["snap-out", "ls"].iter()),
             Parsed::RunCommand {
                 command: "ls".to_owned(),
                 args: vec![],
+                argv0: Option::None,
+                mode: Mode::Exec,
             }
         );
     }
@@ -54,50 +184,232 @@ mod tests {
     #[test]
     fn parses_command_with_args() {
         assert_eq!(
-            parse(vec!["snap-out", "ls", "..", "-a"].iter()),
+            parse(["snap-out", "ls", "..", "-a"].iter()),
+            Parsed::RunCommand {
+                command: "ls".to_owned(),
+                args: ["..", "-a"].iter().map(|s| s.to_string()).collect(),
+                argv0: Option::None,
+                mode: Mode::Exec,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_run_options() {
+        assert_eq!(
+            parse(["snap-out", "--spawn", "--argv0", "-bash", "bash", "-l"].iter()),
+            Parsed::RunCommand {
+                command: "bash".to_owned(),
+                args: vec!["-l".to_owned()],
+                argv0: Some("-bash".to_owned()),
+                mode: Mode::Spawn,
+            }
+        );
+        assert_eq!(
+            parse(["snap-out", "--argv0", "name", "ls", "--spawn"].iter()),
+            Parsed::RunCommand {
+                command: "ls".to_owned(),
+                args: vec!["--spawn".to_owned()],
+                argv0: Some("name".to_owned()),
+                mode: Mode::Exec,
+            }
+        );
+        assert_eq!(
+            parse(
+                [
+                    "snap-out",
+                    "--detach",
+                    "--log",
+                    "out.log",
+                    "--pid-file",
+                    "pid",
+                    "ls"
+                ]
+                .iter()
+            ),
             Parsed::RunCommand {
                 command: "ls".to_owned(),
-                args: vec!["..", "-a"].iter().map(|s| s.to_string()).collect(),
+                args: vec![],
+                argv0: Option::None,
+                mode: Mode::Detach {
+                    log: Some("out.log".to_owned()),
+                    pid_file: Some("pid".to_owned()),
+                },
             }
         );
+        for args in [
+            vec!["snap-out", "--argv0"],
+            vec!["snap-out", "--spawn"],
+            vec!["snap-out", "--spawn", "--bad", "ls"],
+            vec!["snap-out", "--spawn", "--detach", "ls"],
+            vec!["snap-out", "--log", "out.log", "ls"],
+            vec!["snap-out", "--detach", "--pid-file"],
+        ] {
+            match parse(args.iter()) {
+                Parsed::Error(_) => (),
+                result => panic!(
+                    "Should have been an error, but instead returned {:?}",
+                    result
+                ),
+            };
+        }
     }
 
     #[test]
     fn parses_show_help() {
-        assert_eq!(parse(vec!["snap-out", "--help"].iter()), Parsed::ShowHelp,);
-        assert_eq!(parse(vec!["snap-out", "-h"].iter()), Parsed::ShowHelp,);
+        assert_eq!(parse(["snap-out", "--help"].iter()), Parsed::ShowHelp,);
+        assert_eq!(parse(["snap-out", "-h"].iter()), Parsed::ShowHelp,);
     }
 
     #[test]
     fn parses_show_version() {
+        assert_eq!(parse(["snap-out", "--version"].iter()), Parsed::ShowVersion,);
+        assert_eq!(parse(["snap-out", "-v"].iter()), Parsed::ShowVersion,);
+    }
+
+    #[test]
+    fn parses_show_script() {
+        assert_eq!(parse(["snap-out", "--script"].iter()), Parsed::ShowScript,);
+        assert_eq!(parse(["snap-out", "-s"].iter()), Parsed::ShowScript,);
+    }
+
+    #[test]
+    fn parses_show_explanation() {
+        assert_eq!(
+            parse(["snap-out", "--explain"].iter()),
+            Parsed::ShowExplanation { plain: false },
+        );
+        assert_eq!(
+            parse(["snap-out", "-e"].iter()),
+            Parsed::ShowExplanation { plain: false },
+        );
         assert_eq!(
-            parse(vec!["snap-out", "--version"].iter()),
-            Parsed::ShowVersion,
+            parse(["snap-out", "--explain", "--plain"].iter()),
+            Parsed::ShowExplanation { plain: true },
         );
-        assert_eq!(parse(vec!["snap-out", "-v"].iter()), Parsed::ShowVersion,);
+        match parse(["snap-out", "--explain", "--bad"].iter()) {
+            Parsed::Error(_) => (),
+            result => panic!(
+                "Should have been an error, but instead returned {:?}",
+                result
+            ),
+        };
     }
 
     #[test]
-    fn parses_show_script() {
+    fn parses_show_blame() {
         assert_eq!(
-            parse(vec!["snap-out", "--script"].iter()),
-            Parsed::ShowScript,
+            parse(["snap-out", "--blame", "PATH"].iter()),
+            Parsed::ShowBlame {
+                variable: "PATH".to_owned()
+            },
         );
-        assert_eq!(parse(vec!["snap-out", "-s"].iter()), Parsed::ShowScript,);
+        assert_eq!(
+            parse(["snap-out", "-b", "PATH"].iter()),
+            Parsed::ShowBlame {
+                variable: "PATH".to_owned()
+            },
+        );
+        match parse(["snap-out", "--blame"].iter()) {
+            Parsed::Error(_) => (),
+            result => panic!(
+                "Should have been an error, but instead returned {:?}",
+                result
+            ),
+        };
+    }
+
+    #[test]
+    fn parses_record() {
+        assert_eq!(
+            parse(["snap-out", "--record"].iter()),
+            Parsed::Record {
+                command: Option::None,
+                args: vec![],
+            },
+        );
+        assert_eq!(
+            parse(["snap-out", "-r", "app", "--flag"].iter()),
+            Parsed::Record {
+                command: Some("app".to_owned()),
+                args: vec!["--flag".to_owned()],
+            },
+        );
+    }
+
+    #[test]
+    fn parses_open() {
+        assert_eq!(
+            parse(["snap-out", "--open", "a.txt", "https://example.com"].iter()),
+            Parsed::Open {
+                targets: vec!["a.txt".to_owned(), "https://example.com".to_owned()],
+            },
+        );
+        match parse(["snap-out", "--open"].iter()) {
+            Parsed::Error(_) => (),
+            result => panic!(
+                "Should have been an error, but instead returned {:?}",
+                result
+            ),
+        };
+    }
+
+    #[test]
+    fn parses_launch_desktop_file() {
+        assert_eq!(
+            parse(["snap-out", "--desktop-file", "org.example.Merge", "a", "b"].iter()),
+            Parsed::LaunchDesktopFile {
+                id: "org.example.Merge".to_owned(),
+                targets: vec!["a".to_owned(), "b".to_owned()],
+            },
+        );
+        match parse(["snap-out", "--desktop-file"].iter()) {
+            Parsed::Error(_) => (),
+            result => panic!(
+                "Should have been an error, but instead returned {:?}",
+                result
+            ),
+        };
+    }
+
+    #[test]
+    fn parses_open_terminal() {
+        assert_eq!(
+            parse(["snap-out", "--terminal"].iter()),
+            Parsed::OpenTerminal { command: vec![] },
+        );
+        assert_eq!(
+            parse(["snap-out", "--terminal", "--", "vim", "-R"].iter()),
+            Parsed::OpenTerminal {
+                command: vec!["vim".to_owned(), "-R".to_owned()],
+            },
+        );
+        match parse(["snap-out", "--terminal", "vim"].iter()) {
+            Parsed::Error(_) => (),
+            result => panic!(
+                "Should have been an error, but instead returned {:?}",
+                result
+            ),
+        };
+    }
+
+    #[test]
+    fn parses_show_recipe() {
+        assert_eq!(parse(["snap-out", "--recipe"].iter()), Parsed::ShowRecipe);
     }
 
     #[test]
     fn errors_on_bad_arg() {
-        match parse(vec!["snap-out", "--bad"].iter()) {
+        match parse(["snap-out", "--bad"].iter()) {
             Parsed::Error(_) => (),
-            result @ _ => panic!(
+            result => panic!(
                 "Should have been an error, but instead returned {:?}",
                 result
             ),
         };
-        match parse(vec!["snap-out", "-x"].iter()) {
+        match parse(["snap-out", "-x"].iter()) {
             Parsed::Error(_) => (),
-            result @ _ => panic!(
+            result => panic!(
                 "Should have been an error, but instead returned {:?}",
                 result
             ),
@@ -106,7 +418,7 @@ mod tests {
 
     #[test]
     fn none_on_no_args() {
-        assert_eq!(parse(vec!["snap-out"].iter()), Parsed::None,);
+        assert_eq!(parse(["snap-out"].iter()), Parsed::None,);
         assert_eq!(parse((vec![] as Vec<&str>).iter()), Parsed::None,);
     }
 }
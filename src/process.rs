@@ -3,38 +3,118 @@ extern crate procfs;
 use std::collections::HashMap;
 use std::error::Error;
 use std::ffi::OsString;
+use std::path::PathBuf;
 use std::rc::Rc;
 
 pub trait Process {
     fn get_parent(&self) -> Result<Option<Box<dyn Process>>, Box<dyn Error>>;
     fn get_env(&self) -> Rc<HashMap<OsString, OsString>>;
+    /// Why the environment could not be read (in which case get_env() returns an empty one)
+    fn get_env_error(&self) -> Option<String>;
     fn get_pid(&self) -> i32;
+    /// When the process started, in clock ticks since boot, which together with the PID identifies
+    ///   it (PIDs are reused once a process exits)
+    fn get_start_time(&self) -> u64;
+    /// Reads the start time of whichever process has this PID now, which differs from
+    ///   get_start_time() if the process exited and its PID was reused since it was read
+    fn get_current_start_time(&self) -> Result<u64, Box<dyn Error>>;
+    /// The name of the process's executable (possibly truncated), as reported by the kernel
+    fn get_comm(&self) -> String;
+    /// The full path of the process's executable, if it can be read
+    fn get_exe(&self) -> Option<PathBuf>;
+    /// The arguments the process was started with, starting with argv[0]
+    fn get_cmdline(&self) -> Vec<String>;
+    /// The paths of the control groups the process belongs to
+    fn get_cgroups(&self) -> Vec<String>;
+}
+
+/// How many times to try reading a parent that keeps changing before giving up
+pub const PARENT_ATTEMPTS: u32 = 3;
+
+/// Gets the parent of a process, making sure what was read belongs to a single process that is
+///   really the parent
+/// A parent must have started before its child, otherwise the real parent exited and its PID was
+///   reused. The parent's start time is read again after everything else, in case it was replaced
+///   while being read. Either way the parent is looked up again.
+pub fn get_verified_parent(
+    process: &dyn Process,
+) -> Result<Option<Box<dyn Process>>, Box<dyn Error>> {
+    for _ in 0..PARENT_ATTEMPTS {
+        let parent = match process.get_parent()? {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+        if parent.get_start_time() > process.get_start_time() {
+            continue;
+        }
+        if parent.get_current_start_time()? != parent.get_start_time() {
+            continue;
+        }
+        return Ok(Some(parent));
+    }
+    bail!(
+        "The parent of pid {} kept changing while it was being read (it may have exited)",
+        process.get_pid()
+    )
 }
 
 pub struct ProcfsProcess {
     process: procfs::Process,
     env: Rc<HashMap<OsString, OsString>>,
+    env_error: Option<String>,
+    exe: Option<PathBuf>,
+    cmdline: Vec<String>,
+    cgroups: Vec<String>,
 }
 
 impl ProcfsProcess {
-    pub fn from_procfs_process(process: procfs::Process) -> Result<Self, Box<dyn Error>> {
-        let env = Rc::new(process.environ()?);
-        Ok(Self { process, env })
+    /// The environment of processes owned by other users (including setuid ones such as sudo)
+    ///   can't be read, which is recorded rather than treated as an error
+    /// Everything is read up front, so checking the start time afterwards (as
+    ///   get_verified_parent() does) shows if all of it came from the same process.
+    pub fn from_procfs_process(process: procfs::Process) -> Self {
+        let (env, env_error) = match process.environ() {
+            Ok(env) => (env, None),
+            Err(e) => (HashMap::new(), Some(e.to_string())),
+        };
+        let exe = process.exe().ok();
+        let cmdline = process.cmdline().unwrap_or_default();
+        let cgroups = match process.cgroups() {
+            Ok(cgroups) => cgroups.into_iter().map(|c| c.pathname).collect(),
+            Err(_) => Vec::new(),
+        };
+        Self {
+            process,
+            env: Rc::new(env),
+            env_error,
+            exe,
+            cmdline,
+            cgroups,
+        }
     }
 
     pub fn myself() -> Result<Self, Box<dyn Error>> {
-        Self::from_procfs_process(procfs::Process::myself()?)
+        let myself = Self::from_procfs_process(procfs::Process::myself()?);
+        match myself.env_error {
+            Some(e) => bail!("Could not read own environment: {}", e),
+            None => Ok(myself),
+        }
     }
 }
 
 impl Process for ProcfsProcess {
     fn get_parent(&self) -> Result<Option<Box<dyn Process>>, Box<dyn Error>> {
-        let parent_pid = self.process.stat.ppid;
+        // Read stat again, since the parent may have exited and this process been reparented
+        let stat = procfs::Process::new(self.get_pid())?.stat;
+        if stat.starttime != self.process.stat.starttime {
+            bail!("pid {} has exited", self.get_pid());
+        }
+        let parent_pid = stat.ppid;
         if parent_pid <= 1 {
             Ok(None)
         } else {
             let parent_procfs = procfs::Process::new(parent_pid)?;
-            let parent_process = Self::from_procfs_process(parent_procfs)?;
+            let parent_process = Self::from_procfs_process(parent_procfs);
             Ok(Some(Box::new(parent_process)))
         }
     }
@@ -43,9 +123,37 @@ impl Process for ProcfsProcess {
         self.env.clone()
     }
 
+    fn get_env_error(&self) -> Option<String> {
+        self.env_error.clone()
+    }
+
     fn get_pid(&self) -> i32 {
         self.process.pid()
     }
+
+    fn get_start_time(&self) -> u64 {
+        self.process.stat.starttime as u64
+    }
+
+    fn get_current_start_time(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(procfs::Process::new(self.get_pid())?.stat.starttime as u64)
+    }
+
+    fn get_comm(&self) -> String {
+        self.process.stat.comm.clone()
+    }
+
+    fn get_exe(&self) -> Option<PathBuf> {
+        self.exe.clone()
+    }
+
+    fn get_cmdline(&self) -> Vec<String> {
+        self.cmdline.clone()
+    }
+
+    fn get_cgroups(&self) -> Vec<String> {
+        self.cgroups.clone()
+    }
 }
 
 #[cfg(test)]
@@ -53,32 +161,145 @@ pub mod mock {
     use super::*;
     use std::rc::Rc;
 
+    /// The variables of a mock process, as (name, value) pairs
+    pub type MockEnv<'a> = Vec<(&'a str, &'a str)>;
+
+    /// Builds an environment from (name, value) pairs
+    pub fn mock_env(vars: MockEnv) -> HashMap<OsString, OsString> {
+        vars.into_iter()
+            .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+            .collect()
+    }
+
     #[derive(Clone)]
     pub struct MockProcess {
         env: Rc<HashMap<OsString, OsString>>,
+        env_error: Option<String>,
         parent: Option<Rc<MockProcess>>,
+        /// If looking up the parent fails, as when it is hidden by hidepid or has exited
+        parent_hidden: bool,
+        pid: i32,
+        start_time: u64,
+        /// The start time of whichever process has the PID after this one was read
+        current_start_time: u64,
+        cmdline: Vec<String>,
+        cgroup: String,
+    }
+
+    /// A process in a chain built by MockProcess::with_ancestors()
+    pub enum MockAncestor<'a> {
+        Readable(MockEnv<'a>),
+        /// A process whose environment can't be read
+        Unreadable,
+        /// A process that can't be looked up at all, only valid as the first in the chain
+        Hidden,
+        /// A process that reused the PID of the real parent, so started after its child
+        Reused(MockEnv<'a>),
+        /// A process that exited while being read and had its PID reused
+        Replaced(MockEnv<'a>),
+    }
+
+    /// Everything that can be set on a mock process
+    pub struct MockInfo<'a> {
+        pub cmdline: Vec<&'a str>,
+        pub cgroup: &'a str,
+        pub env: MockEnv<'a>,
     }
 
     impl MockProcess {
-        pub fn new(envs: Vec<Vec<(&str, &str)>>) -> MockProcess {
-            assert!(envs.len() > 0);
+        /// Creates a chain of processes, from the toplevel ancestor to the returned process
+        /// Processes are given increasing PIDs starting from 100
+        pub fn new(envs: Vec<MockEnv>) -> MockProcess {
+            Self::with_cmdlines(envs.into_iter().map(|env| (vec![], env)).collect())
+        }
+
+        /// Like new(), but each process also has a command line
+        /// The first argument of the command line is used as the process's executable
+        pub fn with_cmdlines(processes: Vec<(Vec<&str>, MockEnv)>) -> MockProcess {
+            Self::with_info(
+                processes
+                    .into_iter()
+                    .map(|(cmdline, env)| MockInfo {
+                        cmdline,
+                        cgroup: "",
+                        env,
+                    })
+                    .collect(),
+            )
+        }
+
+        /// Like new(), but with full control over each process
+        pub fn with_info(processes: Vec<MockInfo>) -> MockProcess {
+            assert!(!processes.is_empty());
             let mut process: Option<MockProcess> = None;
-            for env in envs {
-                let mut map = HashMap::new();
-                for (var, val) in env {
-                    map.insert(OsString::from(var), OsString::from(val));
-                }
+            for (i, info) in processes.into_iter().enumerate() {
+                let MockInfo {
+                    cmdline,
+                    cgroup,
+                    env,
+                } = info;
                 process = Some(MockProcess {
-                    env: Rc::new(map),
-                    parent: process.map(|p| Rc::new(p)),
+                    env: Rc::new(mock_env(env)),
+                    env_error: None,
+                    parent: process.map(Rc::new),
+                    parent_hidden: false,
+                    pid: 100 + i as i32,
+                    start_time: i as u64,
+                    current_start_time: i as u64,
+                    cmdline: cmdline.into_iter().map(String::from).collect(),
+                    cgroup: String::from(cgroup),
                 });
             }
             process.unwrap()
         }
+
+        /// Like new(), but processes may be unreadable or hidden
+        /// PIDs are assigned as in new(), with the hidden process (if any) getting 100
+        pub fn with_ancestors(ancestors: Vec<MockAncestor>) -> MockProcess {
+            let mut ancestors = ancestors.into_iter().peekable();
+            let hidden = matches!(ancestors.peek(), Some(MockAncestor::Hidden));
+            let mut process: Option<MockProcess> = None;
+            let mut pid = 100;
+            if hidden {
+                ancestors.next();
+                pid += 1;
+            }
+            for ancestor in ancestors {
+                let mut child = match ancestor {
+                    MockAncestor::Readable(env) => Self::new(vec![env]),
+                    MockAncestor::Unreadable => {
+                        let mut child = Self::new(vec![vec![]]);
+                        child.env_error = Some(String::from("Permission denied"));
+                        child
+                    }
+                    MockAncestor::Reused(env) => {
+                        let mut child = Self::new(vec![env]);
+                        child.start_time = 1000;
+                        child.current_start_time = 1000;
+                        child
+                    }
+                    MockAncestor::Replaced(env) => {
+                        let mut child = Self::new(vec![env]);
+                        child.current_start_time = 1000;
+                        child
+                    }
+                    MockAncestor::Hidden => panic!("Only the first process can be hidden"),
+                };
+                child.pid = pid;
+                child.parent_hidden = process.is_none() && hidden;
+                child.parent = process.map(Rc::new);
+                process = Some(child);
+                pid += 1;
+            }
+            process.expect("No processes given")
+        }
     }
 
     impl Process for MockProcess {
         fn get_parent(&self) -> Result<Option<Box<dyn Process>>, Box<dyn Error>> {
+            if self.parent_hidden {
+                bail!("No such process");
+            }
             Ok(self
                 .parent
                 .as_ref()
@@ -89,8 +310,40 @@ pub mod mock {
             self.env.clone()
         }
 
+        fn get_env_error(&self) -> Option<String> {
+            self.env_error.clone()
+        }
+
         fn get_pid(&self) -> i32 {
-            1
+            self.pid
+        }
+
+        fn get_start_time(&self) -> u64 {
+            self.start_time
+        }
+
+        fn get_current_start_time(&self) -> Result<u64, Box<dyn Error>> {
+            Ok(self.current_start_time)
+        }
+
+        fn get_comm(&self) -> String {
+            String::from("mock")
+        }
+
+        fn get_exe(&self) -> Option<PathBuf> {
+            self.cmdline.first().map(PathBuf::from)
+        }
+
+        fn get_cmdline(&self) -> Vec<String> {
+            self.cmdline.clone()
+        }
+
+        fn get_cgroups(&self) -> Vec<String> {
+            if self.cgroup.is_empty() {
+                Vec::new()
+            } else {
+                vec![self.cgroup.clone()]
+            }
         }
     }
 }
@@ -102,7 +355,7 @@ mod tests {
 
     fn assert_maps_to(map: &HashMap<OsString, OsString>, key: &OsStr, val: Option<&OsStr>) {
         let val_in_map = map.get(key);
-        let expected_val = val.map(|v| OsString::from(v));
+        let expected_val = val.map(OsString::from);
         if val_in_map != expected_val.as_ref() {
             panic!(
                 "Key {:?} expected to map to {:?} but actually maps to {:?}",
@@ -116,6 +369,39 @@ mod tests {
         let _ = ProcfsProcess::myself().expect("Could not open myself process");
     }
 
+    #[test]
+    fn detects_myself_exe() {
+        let myself = ProcfsProcess::myself().expect("Could not open myself process");
+        assert_eq!(
+            myself.get_exe(),
+            Some(std::env::current_exe().expect("Could not get current exe"))
+        );
+        assert!(!myself.get_comm().is_empty());
+        assert!(!myself.get_cgroups().is_empty());
+        assert_eq!(
+            myself.get_cmdline(),
+            std::env::args().collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn reads_everything_before_the_process_exits() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .expect("Failed to start child");
+        let process = ProcfsProcess::from_procfs_process(
+            procfs::Process::new(child.id() as i32).expect("Could not open child process"),
+        );
+        child.kill().expect("Failed to kill child");
+        child.wait().expect("Failed to wait for child");
+        // What was read still describes the child, and it can be told it has gone since
+        assert!(process
+            .get_exe()
+            .is_some_and(|exe| exe.file_name() == Some(std::ffi::OsStr::new("sleep"))));
+        assert!(process.get_current_start_time().is_err());
+    }
+
     #[test]
     fn can_get_myself_parent_process() {
         let myself = ProcfsProcess::myself().expect("Could not open myself process");
@@ -125,6 +411,29 @@ mod tests {
             .expect("Had no parent_process");
     }
 
+    #[test]
+    fn verifies_parent_identity() {
+        use mock::MockAncestor::*;
+        let process = mock::MockProcess::with_ancestors(vec![Readable(vec![]), Readable(vec![])]);
+        let parent = get_verified_parent(&process)
+            .expect("Could not get parent")
+            .expect("Had no parent");
+        assert_eq!(parent.get_pid(), 100);
+        for ancestor in [Reused(vec![]), Replaced(vec![])] {
+            let process = mock::MockProcess::with_ancestors(vec![ancestor, Readable(vec![])]);
+            assert!(get_verified_parent(&process).is_err());
+        }
+    }
+
+    #[test]
+    fn can_get_verified_myself_parent_process() {
+        let myself = ProcfsProcess::myself().expect("Could not open myself process");
+        let parent = get_verified_parent(&myself)
+            .expect("Could not get parent process")
+            .expect("Had no parent_process");
+        assert!(parent.get_start_time() <= myself.get_start_time());
+    }
+
     #[test]
     fn can_traverse_to_top() {
         let mut process = Some(Box::new(
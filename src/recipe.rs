@@ -0,0 +1,375 @@
+use super::record;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// Environment variable that carries the changes to later runs of snap-out
+pub const RECIPE_ENV_VAR: &str = "SNAP_OUT_RECIPE";
+/// Where the key recipes are signed with is kept, relative to record::RECORD_DIR
+pub const KEY_FILE: &str = "recipe.key";
+/// Changes whenever the format of recipes does
+const VERSION: &str = "2";
+
+type Key = [u8; 32];
+/// Variables to set (or unset if the value is None)
+pub type Changes = Vec<(OsString, Option<OsString>)>;
+
+/// What a recipe decided for one variable
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Decision {
+    pub name: OsString,
+    /// The value the variable had when the recipe was made
+    pub from: Option<OsString>,
+    /// The value to give it instead
+    pub to: Option<OsString>,
+}
+
+/// Returns where the key is kept, under XDG_RUNTIME_DIR
+/// The key is only used if it belongs to the current user and no one else can write to it (see
+///   record::read_private()). Whether other processes of that user can read it depends on the
+///   snap's confinement: strictly confined snaps get an XDG_RUNTIME_DIR of their own, but
+///   classic and unconfined snaps share the user's, so any process of the user could forge a
+///   recipe for them.
+pub fn get_key_path(runtime_dir: Option<&OsStr>) -> Option<PathBuf> {
+    let runtime_dir = Path::new(runtime_dir?);
+    if !runtime_dir.is_absolute() {
+        return None;
+    }
+    Some(runtime_dir.join(record::RECORD_DIR).join(KEY_FILE))
+}
+
+/// Loads the key recipes are signed with
+pub fn load_key(path: &Path) -> Result<Key, Box<dyn Error>> {
+    match record::read_private(path)? {
+        Some(bytes) if bytes.len() == 32 => {
+            let mut key = [0; 32];
+            key.copy_from_slice(&bytes);
+            Ok(key)
+        }
+        Some(_) => bail!("Invalid recipe key {}", path.display()),
+        None => bail!("No recipe key at {}", path.display()),
+    }
+}
+
+/// Loads the key recipes are signed with, generating it first if there isn't one
+/// The key file is created exclusively, so when runs race to create it they all end up with the
+///   one that won
+pub fn load_or_create_key(path: &Path) -> Result<Key, Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+    {
+        Ok(mut file) => {
+            let mut key = [0; 32];
+            std::fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+            file.write_all(&key)?;
+            Ok(key)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => load_key(path),
+        Err(e) => Err(format!("Could not create {}: {}", path.display(), e).into()),
+    }
+}
+
+/// Only characters that are safe in a shell word are left as-is, so the recipe can be exported by
+///   a script without quoting
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for byte in bytes {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'.' | b'_' | b'-' | b'+' => {
+                escaped.push(*byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    Some(bytes)
+}
+
+/// Returns the HMAC-SHA256 of the instance and decisions, ready to be finalized or verified
+fn get_mac(key: &Key, instance: &str, entries: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(VERSION.as_bytes());
+    mac.update(b"\0");
+    mac.update(instance.as_bytes());
+    mac.update(b"\0");
+    mac.update(entries.as_bytes());
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    // An odd length leaves half a byte at the end, which get() turns into None
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Encodes a value in a decision, as - if unset or + followed by the value
+fn encode_value(value: &Option<OsString>) -> String {
+    match value {
+        Some(value) => format!("+{}", escape(value.as_bytes())),
+        None => String::from("-"),
+    }
+}
+
+fn decode_value(text: &str) -> Option<Option<OsString>> {
+    if text == "-" {
+        Some(None)
+    } else {
+        Some(Some(OsString::from_vec(unescape(text.strip_prefix('+')?)?)))
+    }
+}
+
+/// Builds a recipe in the form VERSION:INSTANCE:SIGNATURE:DECISIONS, where DECISIONS is a comma
+///   separated list of NAME=FROM=TO, FROM being the value the variable has now and TO the one it
+///   should get. The signature is an HMAC-SHA256 of the rest.
+pub fn encode(decisions: &[Decision], instance: &str, key: &Key) -> String {
+    let mut sorted: Vec<_> = decisions.iter().collect();
+    sorted.sort();
+    let entries: Vec<String> = sorted
+        .into_iter()
+        .map(|decision| {
+            format!(
+                "{}={}={}",
+                escape(decision.name.as_bytes()),
+                encode_value(&decision.from),
+                encode_value(&decision.to)
+            )
+        })
+        .collect();
+    let entries = entries.join(",");
+    let instance = escape(instance.as_bytes());
+    let signature = to_hex(&get_mac(key, &instance, &entries).finalize().into_bytes());
+    format!("{}:{}:{}:{}", VERSION, instance, signature, entries)
+}
+
+/// Reads a recipe, making sure it was signed with the key and made for the given instance
+pub fn decode(recipe: &str, instance: &str, key: &Key) -> Result<Vec<Decision>, Box<dyn Error>> {
+    let mut parts = recipe.splitn(4, ':');
+    let (version, recipe_instance, signature, entries) =
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(version), Some(instance), Some(signature), Some(entries)) => {
+                (version, instance, signature, entries)
+            }
+            _ => bail!("Invalid recipe"),
+        };
+    if version != VERSION {
+        bail!("Recipe is from an incompatible version of snap-out");
+    }
+    if recipe_instance != escape(instance.as_bytes()) {
+        bail!("Recipe was made for a different snap");
+    }
+    let signature = match from_hex(signature) {
+        Some(signature) => signature,
+        None => bail!("Invalid recipe signature"),
+    };
+    // Compares in constant time, so the signature can't be guessed a byte at a time
+    if get_mac(key, recipe_instance, entries)
+        .verify_slice(&signature)
+        .is_err()
+    {
+        bail!("Recipe signature does not match");
+    }
+    let mut decisions = Vec::new();
+    for entry in entries.split(',').filter(|entry| !entry.is_empty()) {
+        let mut fields = entry.split('=');
+        let (name, from, to) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(from), Some(to), None) => (name, from, to),
+            _ => bail!("Invalid entry in recipe"),
+        };
+        let name = match unescape(name) {
+            Some(name) if !name.is_empty() => OsString::from_vec(name),
+            _ => bail!("Invalid variable name in recipe"),
+        };
+        match (decode_value(from), decode_value(to)) {
+            (Some(from), Some(to)) => decisions.push(Decision { name, from, to }),
+            _ => bail!("Invalid value for {:?} in recipe", name),
+        }
+    }
+    Ok(decisions)
+}
+
+/// Returns the changes the decisions make to the current environment
+/// The recipe doesn't know what to do with a variable that no longer has the value it was made
+///   from, or one it has no decision for that points into the snap (such as one added by a
+///   wrapper since, as told by points_into_snap), so then None is returned. Decisions to keep a
+///   value as it is don't make a change.
+pub fn apply(
+    decisions: &[Decision],
+    env: &HashMap<OsString, OsString>,
+    points_into_snap: impl Fn(&OsStr, &OsStr) -> bool,
+) -> Option<Changes> {
+    let undecided_into_snap = env.iter().any(|(name, value)| {
+        !decisions.iter().any(|decision| decision.name == *name) && points_into_snap(name, value)
+    });
+    if undecided_into_snap {
+        return None;
+    }
+    let mut changes = Vec::new();
+    for decision in decisions {
+        if env.get(&decision.name) != decision.from.as_ref() {
+            return None;
+        }
+        if decision.to != decision.from {
+            changes.push((decision.name.clone(), decision.to.clone()));
+        }
+    }
+    Some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::process::mock::mock_env;
+    use super::*;
+
+    const KEY: Key = [7; 32];
+
+    fn decision(name: &str, from: Option<&str>, to: Option<&str>) -> Decision {
+        Decision {
+            name: OsString::from(name),
+            from: from.map(OsString::from),
+            to: to.map(OsString::from),
+        }
+    }
+
+    fn mock_decisions() -> Vec<Decision> {
+        vec![
+            decision("PATH", Some("/snap/foo/1/bin:/usr/bin"), Some("/usr/bin")),
+            decision("GTK_PATH", Some("/snap/foo/1/gtk"), None),
+            decision("EDITOR", None, Some("vim")),
+            decision("ODD", Some(""), Some("a,b=c d%e\n;$(x)")),
+        ]
+    }
+
+    #[test]
+    fn escapes_for_shell() {
+        let escaped = escape(b"a,b=c d%e\n;$(x)");
+        assert!(escaped
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-+%".contains(c)));
+        assert_eq!(unescape(&escaped), Some(b"a,b=c d%e\n;$(x)".to_vec()));
+        assert_eq!(unescape("%4"), None);
+        assert_eq!(unescape("%zz"), None);
+    }
+
+    #[test]
+    fn round_trips_decisions() {
+        let recipe = encode(&mock_decisions(), "foo", &KEY);
+        assert!(recipe.starts_with("2:foo:"));
+        assert!(recipe
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-+%:,=".contains(c)));
+        let mut expected = mock_decisions();
+        expected.sort();
+        assert_eq!(
+            decode(&recipe, "foo", &KEY).expect("Failed to decode"),
+            expected
+        );
+        let empty = encode(&[], "foo", &KEY);
+        assert_eq!(
+            decode(&empty, "foo", &KEY).expect("Failed to decode"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn rejects_spoofed_recipes() {
+        let recipe = encode(&mock_decisions(), "foo", &KEY);
+        assert!(decode(&recipe, "bar", &KEY).is_err());
+        assert!(decode(&recipe, "foo", &[8; 32]).is_err());
+        let tampered = recipe.replace("GTK_PATH", "GTK_PATX");
+        assert!(decode(&tampered, "foo", &KEY).is_err());
+        let moved = recipe.replacen("2:foo:", "2:bar:", 1);
+        assert!(decode(&moved, "bar", &KEY).is_err());
+        let truncated = recipe.replacen("2:foo:", "2:foo:00", 1);
+        assert!(decode(&truncated, "foo", &KEY).is_err());
+        assert!(decode("garbage", "foo", &KEY).is_err());
+    }
+
+    #[test]
+    fn applies_only_to_unchanged_values() {
+        let mut decisions = mock_decisions();
+        decisions.push(decision(
+            "KEPT",
+            Some("/snap/foo/1/kept"),
+            Some("/snap/foo/1/kept"),
+        ));
+        let points_into_snap = |_: &OsStr, value: &OsStr| value.as_bytes().starts_with(b"/snap/");
+        let mut env = mock_env(vec![
+            ("PATH", "/snap/foo/1/bin:/usr/bin"),
+            ("GTK_PATH", "/snap/foo/1/gtk"),
+            ("ODD", ""),
+            ("KEPT", "/snap/foo/1/kept"),
+            ("HOME", "/home/alice"),
+        ]);
+        assert_eq!(
+            apply(&decisions, &env, points_into_snap),
+            Some(vec![
+                (OsString::from("PATH"), Some(OsString::from("/usr/bin"))),
+                (OsString::from("GTK_PATH"), None),
+                (OsString::from("EDITOR"), Some(OsString::from("vim"))),
+                (
+                    OsString::from("ODD"),
+                    Some(OsString::from("a,b=c d%e\n;$(x)"))
+                ),
+            ])
+        );
+        // A variable without a decision that points into the snap needs detecting
+        let mut added = env.clone();
+        added.insert(
+            OsString::from("PYTHONPATH"),
+            OsString::from("/snap/foo/1/lib/python"),
+        );
+        assert_eq!(apply(&decisions, &added, points_into_snap), None);
+        env.insert(
+            OsString::from("PATH"),
+            OsString::from("/home/alice/bin:/snap/foo/1/bin:/usr/bin"),
+        );
+        assert_eq!(apply(&decisions, &env, points_into_snap), None);
+    }
+
+    #[test]
+    fn creates_key_once() {
+        let dir = std::env::temp_dir().join(format!("snap-out-recipe-test-{}", std::process::id()));
+        let path = get_key_path(Some(dir.as_os_str())).expect("Failed to build path");
+        assert!(load_key(&path).is_err());
+        let key = load_or_create_key(&path).expect("Failed to create key");
+        assert_eq!(load_or_create_key(&path).expect("Failed to load key"), key);
+        assert_eq!(load_key(&path).expect("Failed to load key"), key);
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,261 @@
+use super::environments::Snapshot;
+use super::fallback;
+use super::process::ProcfsProcess;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// Where recordings are kept, relative to XDG_RUNTIME_DIR
+pub const RECORD_DIR: &str = "snap-out";
+
+/// A recorded environment, along with the process that launched the snap which recorded it
+pub struct Recording {
+    pub launch: Snapshot,
+    pub env: HashMap<OsString, OsString>,
+}
+
+/// Returns the directory recordings are kept in
+/// XDG_RUNTIME_DIR is only readable by its user (and inside a snap is specific to the instance),
+///   so the recordings are too
+pub fn get_dir(runtime_dir: Option<&OsStr>) -> Option<PathBuf> {
+    let runtime_dir = Path::new(runtime_dir?);
+    if runtime_dir.is_absolute() {
+        Some(runtime_dir.join(RECORD_DIR))
+    } else {
+        None
+    }
+}
+
+/// Returns where the external environment of one launch of a snap instance is recorded, named
+///   after the PID and start time of the process that recorded it (which the rest of the command
+///   chain runs as)
+pub fn get_path(dir: &Path, instance: &str, pid: i32, start_time: u64) -> Option<PathBuf> {
+    if instance.is_empty() || instance.starts_with('.') || instance.contains('/') {
+        return None;
+    }
+    Some(dir.join(format!("{}.{}-{}.env", instance, pid, start_time)))
+}
+
+/// Returns the PID and start time from the name of a recording of the instance
+fn parse_name(name: &OsStr, instance: &str) -> Option<(i32, u64)> {
+    let key = name
+        .to_str()?
+        .strip_prefix(instance)?
+        .strip_prefix('.')?
+        .strip_suffix(".env")?;
+    let (pid, start_time) = key.split_once('-')?;
+    Some((pid.parse().ok()?, start_time.parse().ok()?))
+}
+
+/// Returns if a process is still running, rather than another one that reused its PID
+pub fn is_running(pid: i32, start_time: u64) -> bool {
+    procfs::Process::new(pid).is_ok_and(|process| process.stat.starttime as u64 == start_time)
+}
+
+/// Writes NAME=VALUE entries separated by NUL bytes, sorted by name
+fn serialize(env: &HashMap<OsString, OsString>) -> Vec<u8> {
+    let mut entries: Vec<_> = env.iter().collect();
+    entries.sort();
+    let mut bytes = Vec::new();
+    for (name, value) in entries {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(b'=');
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+/// Writes a file only the current user can read, replacing it in one step so another process
+///   reading it at the same time never sees half of it
+pub fn write_private(path: &Path, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let dir = match path.parent() {
+        Some(dir) => dir,
+        None => bail!("Invalid path {}", path.display()),
+    };
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    let temp_path = dir.join(format!(".{}.tmp", std::process::id()));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    file.write_all(bytes)?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Reads a file written by write_private(), ignoring files that don't exist and refusing ones
+///   that belong to someone else or that others could have written to
+pub fn read_private(path: &Path) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let uid = procfs::Process::myself()?.owner;
+    if metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+        bail!(
+            "Ignoring {}, as it is not owned and only writable by the current user",
+            path.display()
+        );
+    }
+    Ok(Some(std::fs::read(path)?))
+}
+
+/// Saves the environment a snap instance was launched with, keyed to the launch process, and
+///   removes the recordings of launches that have ended
+pub fn save(
+    dir: &Path,
+    instance: &str,
+    launch: &Snapshot,
+    env: &HashMap<OsString, OsString>,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let path = match get_path(dir, instance, launch.pid, launch.start_time) {
+        Some(path) => path,
+        None => bail!("Invalid instance name {}", instance),
+    };
+    write_private(&path, &serialize(env))?;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some((pid, start_time)) = parse_name(&name, instance) {
+            if !is_running(pid, start_time) {
+                let _ = std::fs::remove_file(dir.join(&name));
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Loads the recording of the launch the current process belongs to, if there is one
+/// A launch is only used while its process is still running, and if it is in the same cgroup as
+///   the current process. snapd starts every launch in a scope of its own, which processes stay
+///   in even once reparented, so this holds where the ancestors no longer lead to the launch.
+pub fn find(
+    dir: &Path,
+    instance: &str,
+    myself: &Snapshot,
+) -> Result<Option<Recording>, Box<dyn Error>> {
+    if myself.cgroups.is_empty() {
+        return Ok(None);
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        let (pid, start_time) = match parse_name(&name, instance) {
+            Some(key) => key,
+            None => continue,
+        };
+        let launch = match procfs::Process::new(pid) {
+            Ok(process) if process.stat.starttime as u64 == start_time => {
+                Snapshot::from_process(&ProcfsProcess::from_procfs_process(process))
+            }
+            _ => continue,
+        };
+        // The launch may have exited and its PID been reused while it was being read
+        if !is_running(pid, start_time) {
+            continue;
+        }
+        if launch.cgroups != myself.cgroups {
+            continue;
+        }
+        if let Some(bytes) = read_private(&dir.join(&name))? {
+            return Ok(Some(Recording {
+                launch,
+                env: fallback::parse_nul_separated(&bytes),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::process::mock::mock_env;
+    use super::*;
+
+    fn sample_env() -> HashMap<OsString, OsString> {
+        mock_env(vec![("PATH", "/usr/bin:/bin"), ("MULTILINE", "a\nb=c")])
+    }
+
+    #[test]
+    fn builds_path() {
+        let dir = get_dir(Some(OsStr::new("/run/user/1000"))).expect("Failed to build dir");
+        let path = get_path(&dir, "foo_beta", 1234, 5678);
+        assert_eq!(
+            path,
+            Some(PathBuf::from(
+                "/run/user/1000/snap-out/foo_beta.1234-5678.env"
+            ))
+        );
+        assert_eq!(
+            parse_name(path.unwrap().file_name().unwrap(), "foo_beta"),
+            Some((1234, 5678))
+        );
+        assert_eq!(
+            parse_name(OsStr::new("foo_beta.1234-5678.env"), "foo"),
+            None
+        );
+        assert_eq!(get_dir(None), None);
+        assert_eq!(get_dir(Some(OsStr::new("relative"))), None);
+        assert_eq!(get_path(&dir, "../foo", 1, 1), None);
+        assert_eq!(get_path(&dir, "", 1, 1), None);
+    }
+
+    #[test]
+    fn serializes_sorted() {
+        assert_eq!(
+            serialize(&sample_env()),
+            b"MULTILINE=a\nb=c\0PATH=/usr/bin:/bin\0"
+        );
+    }
+
+    #[test]
+    fn saves_and_finds_running_launch() {
+        let dir = std::env::temp_dir().join(format!("snap-out-record-test-{}", std::process::id()));
+        let myself =
+            Snapshot::from_process(&ProcfsProcess::myself().expect("Failed to read own process"));
+        assert!(find(&dir, "foo", &myself)
+            .expect("Failed to find in missing dir")
+            .is_none());
+        let mut ended = Snapshot::from_source(fallback::Source::Recorded, HashMap::new());
+        ended.pid = myself.pid;
+        ended.start_time = u64::MAX;
+        let ended_path = save(&dir, "foo", &ended, &sample_env()).expect("Failed to save");
+        let path = save(&dir, "foo", &myself, &sample_env()).expect("Failed to save");
+        // Saving removes recordings of launches that have ended
+        assert!(!ended_path.exists());
+        let mode = std::fs::metadata(&path).expect("Failed to stat").mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let recording = find(&dir, "foo", &myself)
+            .expect("Failed to find")
+            .expect("Should have found the recording");
+        assert_eq!(recording.launch.pid, myself.pid);
+        assert_eq!(recording.env, sample_env());
+        // Other instances and other launches (in other cgroups) don't use it
+        assert!(find(&dir, "bar", &myself)
+            .expect("Failed to find")
+            .is_none());
+        let mut elsewhere = Snapshot::from_source(fallback::Source::Recorded, HashMap::new());
+        elsewhere.cgroups = vec![String::from("/other.scope")];
+        assert!(find(&dir, "foo", &elsewhere)
+            .expect("Failed to find")
+            .is_none());
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o666))
+            .expect("Failed to set permissions");
+        assert!(find(&dir, "foo", &myself).is_err());
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Config file that applies to every snap on the system
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/snap-out/rules.conf";
+/// Config file shipped inside the snap, relative to $SNAP (or $APPDIR for an AppImage)
+pub const SNAP_CONFIG_PATH: &str = "etc/snap-out/rules.conf";
+/// Config file of the user, relative to $XDG_CONFIG_HOME (~/.config by default)
+pub const USER_CONFIG_PATH: &str = "snap-out/rules.conf";
+
+/// What should be done with a variable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Detect what the snap changed and undo it
+    Auto,
+    /// Leave the current value as-is
+    Keep,
+    /// Always clear the variable
+    Unset,
+    /// Always use the value from outside the snap
+    Restore,
+}
+
+impl Policy {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(Policy::Auto),
+            "keep" => Some(Policy::Keep),
+            "unset" => Some(Policy::Unset),
+            "restore" => Some(Policy::Restore),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Rule {
+    pattern: String,
+    policy: Policy,
+}
+
+/// Per-variable policies loaded from config files
+/// Each line of a config file is a rule in the form `PATTERN = POLICY`, where PATTERN is a
+///   variable name that may contain * and ? wildcards and POLICY is auto, keep, unset or restore
+/// Rules after a `[SNAP_NAME]` header only apply inside the snap of that name (or AppImage of that
+///   file name, without the extension), the header may also contain wildcards
+/// When multiple rules match a variable the last one wins
+#[derive(Debug, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    warnings: Vec<String>,
+}
+
+/// Returns if text matches a pattern where * matches any number of characters and ? matches one
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last * in the pattern, and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+impl Rules {
+    /// Parses the contents of a config file, keeping only the rules that apply to this snap
+    /// Lines that can't be parsed are skipped, and described in the returned warnings
+    pub fn parse(&mut self, text: &str, snap_name: Option<&str>) -> Vec<String> {
+        let mut section_applies = true;
+        let mut warnings = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if let Err(e) = self.parse_line(line, snap_name, &mut section_applies) {
+                warnings.push(format!("line {}: {}", i + 1, e));
+            }
+        }
+        warnings
+    }
+
+    fn parse_line(
+        &mut self,
+        line: &str,
+        snap_name: Option<&str>,
+        section_applies: &mut bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        if line.starts_with('[') {
+            if !line.ends_with(']') {
+                // The rules that follow are for a snap we can't tell, so none of them apply
+                *section_applies = false;
+                bail!("unterminated section header");
+            }
+            let section = line[1..line.len() - 1].trim();
+            *section_applies = match snap_name {
+                Some(name) => glob_matches(section, name),
+                None => false,
+            };
+            return Ok(());
+        }
+        let (pattern, policy) = match line.find('=') {
+            Some(split) => (line[..split].trim(), line[split + 1..].trim()),
+            None => bail!("expected PATTERN = POLICY"),
+        };
+        let policy = match Policy::parse(policy) {
+            Some(policy) => policy,
+            None => bail!("unknown policy {:?}", policy),
+        };
+        if pattern.is_empty() {
+            bail!("missing variable name");
+        }
+        if *section_applies {
+            self.rules.push(Rule {
+                pattern: String::from(pattern),
+                policy,
+            });
+        }
+        Ok(())
+    }
+
+    /// Loads every config file that exists, later files overriding earlier ones
+    /// A file that can't be read is skipped, and like lines that can't be parsed this is kept as a
+    ///   warning instead of stopping the others from applying
+    pub fn load(paths: &[PathBuf], snap_name: Option<&str>) -> Self {
+        let mut rules = Rules::default();
+        for path in paths {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    rules
+                        .warnings
+                        .push(format!("Failed to read {}: {}", path.display(), e));
+                    continue;
+                }
+            };
+            for warning in rules.parse(&text, snap_name) {
+                rules
+                    .warnings
+                    .push(format!("Skipping {} {}", path.display(), warning));
+            }
+        }
+        rules
+    }
+
+    /// Returns what went wrong loading the config files
+    pub fn get_warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns the policy for the given variable
+    pub fn get_policy(&self, name: &OsStr) -> Policy {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return Policy::Auto,
+        };
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| glob_matches(&rule.pattern, name))
+            .map_or(Policy::Auto, |rule| rule.policy)
+    }
+}
+
+/// Returns the system, snap and user config paths, in the order they should be applied
+/// root is where the sandbox is mounted, and external the environment from outside of it
+pub fn config_paths(root: Option<&Path>, external: &HashMap<OsString, OsString>) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(SYSTEM_CONFIG_PATH)];
+    if let Some(root) = root {
+        paths.push(root.join(SNAP_CONFIG_PATH));
+    }
+    let config_home = match external.get(OsStr::new("XDG_CONFIG_HOME")) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => external
+            .get(OsStr::new("HOME"))
+            .map(|home| Path::new(home).join(".config")),
+    };
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join(USER_CONFIG_PATH));
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str, snap_name: Option<&str>) -> Rules {
+        let mut rules = Rules::default();
+        assert_eq!(rules.parse(text, snap_name), Vec::<String>::new());
+        rules
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_matches("GTK_PATH", "GTK_PATH"));
+        assert!(glob_matches("GTK_*", "GTK_PATH"));
+        assert!(glob_matches("*_PATH", "LD_LIBRARY_PATH"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("LC_?", "LC_A"));
+        assert!(glob_matches("*LIB*PATH", "LD_LIBRARY_PATH"));
+        assert!(!glob_matches("GTK_*", "GDK_PATH"));
+        assert!(!glob_matches("LC_?", "LC_ALL"));
+        assert!(!glob_matches("PATH", "PATH2"));
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = parse(
+            "# comment\nGIT_ASKPASS = keep\n\nGTK_* = unset\nLANG=restore\n",
+            None,
+        );
+        assert_eq!(rules.get_policy(OsStr::new("GIT_ASKPASS")), Policy::Keep);
+        assert_eq!(rules.get_policy(OsStr::new("GTK_PATH")), Policy::Unset);
+        assert_eq!(rules.get_policy(OsStr::new("LANG")), Policy::Restore);
+        assert_eq!(rules.get_policy(OsStr::new("PATH")), Policy::Auto);
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let rules = parse("GTK_* = unset\nGTK_THEME = keep\n", None);
+        assert_eq!(rules.get_policy(OsStr::new("GTK_THEME")), Policy::Keep);
+        assert_eq!(rules.get_policy(OsStr::new("GTK_PATH")), Policy::Unset);
+        let mut rules = parse("GTK_THEME = keep\n", None);
+        rules.parse("GTK_* = auto\n", None);
+        assert_eq!(rules.get_policy(OsStr::new("GTK_THEME")), Policy::Auto);
+    }
+
+    #[test]
+    fn only_applies_sections_for_this_snap() {
+        let text = "A = keep\n[code]\nB = keep\n[other]\nC = keep\n[code*]\nD = keep\n";
+        let rules = parse(text, Some("code"));
+        assert_eq!(rules.get_policy(OsStr::new("A")), Policy::Keep);
+        assert_eq!(rules.get_policy(OsStr::new("B")), Policy::Keep);
+        assert_eq!(rules.get_policy(OsStr::new("C")), Policy::Auto);
+        assert_eq!(rules.get_policy(OsStr::new("D")), Policy::Keep);
+        let rules = parse(text, None);
+        assert_eq!(rules.get_policy(OsStr::new("A")), Policy::Keep);
+        assert_eq!(rules.get_policy(OsStr::new("B")), Policy::Auto);
+    }
+
+    #[test]
+    fn skips_bad_rules() {
+        let mut rules = Rules::default();
+        let text = "FOO\nA = keep\nB = sometimes\n= keep\n[code\nC = keep\n[*]\nD = keep\n";
+        assert_eq!(
+            rules.parse(text, Some("code")),
+            vec![
+                "line 1: expected PATTERN = POLICY",
+                "line 3: unknown policy \"sometimes\"",
+                "line 4: missing variable name",
+                "line 5: unterminated section header",
+            ]
+        );
+        assert_eq!(rules.get_policy(OsStr::new("A")), Policy::Keep);
+        assert_eq!(rules.get_policy(OsStr::new("C")), Policy::Auto);
+        assert_eq!(rules.get_policy(OsStr::new("D")), Policy::Keep);
+    }
+
+    #[test]
+    fn skips_missing_config_files() {
+        let rules = Rules::load(&[PathBuf::from("/nonexistent/snap-out.conf")], None);
+        assert_eq!(rules, Rules::default());
+    }
+
+    #[test]
+    fn warns_about_bad_config_files() {
+        let dir = std::env::temp_dir().join(format!("snap-out-rules-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let bad = dir.join("bad.conf");
+        let good = dir.join("good.conf");
+        std::fs::write(&bad, "A = keep\nB = sometimes\n").expect("Failed to write config");
+        std::fs::write(&good, "C = unset\n").expect("Failed to write config");
+        let rules = Rules::load(&[dir.clone(), bad.clone(), good], None);
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+        assert_eq!(rules.get_warnings().len(), 2);
+        assert!(rules.get_warnings()[0].starts_with(&format!("Failed to read {}", dir.display())));
+        assert_eq!(
+            rules.get_warnings()[1],
+            format!(
+                "Skipping {} line 2: unknown policy \"sometimes\"",
+                bad.display()
+            )
+        );
+        assert_eq!(rules.get_policy(OsStr::new("A")), Policy::Keep);
+        assert_eq!(rules.get_policy(OsStr::new("C")), Policy::Unset);
+    }
+}
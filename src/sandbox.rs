@@ -0,0 +1,394 @@
+use super::environments::Snapshot;
+use super::locations::Locations;
+use super::variable::{self, KnownVariable};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// What showed that a process is inside a sandbox
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    /// The sandbox's variables are set
+    Environment,
+    /// The process's executable is inside the sandbox
+    Executable,
+    /// The process is in a control group the sandbox created
+    Cgroup,
+    /// The process is one of the sandbox's launchers
+    Launcher,
+}
+
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Signal::Environment => "environment",
+            Signal::Executable => "executable",
+            Signal::Cgroup => "cgroup",
+            Signal::Launcher => "launcher",
+        })
+    }
+}
+
+/// A kind of sandbox that modifies the environment of the programs inside it
+/// Each method that takes an environment expects the one of the first process inside the sandbox
+pub trait Sandbox: std::fmt::Debug {
+    /// A short human readable name, such as "snap"
+    fn get_name(&self) -> &'static str;
+    /// Returns if a process with the given environment is inside the sandbox
+    fn is_inside(&self, env: &HashMap<OsString, OsString>) -> bool;
+    /// Returns what shows the process is inside the sandbox, or None if it is outside
+    /// visited are the processes already looked at on the way up from the current process (which
+    ///   comes first), and tell which sandbox is being escaped. By default only the process's
+    ///   environment is checked.
+    fn get_signal(&self, process: &Snapshot, _visited: &[Snapshot]) -> Option<Signal> {
+        if self.is_inside(&process.env) {
+            Some(Signal::Environment)
+        } else {
+            None
+        }
+    }
+    /// Returns the directory the sandbox is mounted at
+    fn get_root(&self, env: &HashMap<OsString, OsString>) -> Option<PathBuf>;
+    /// Returns the name of the app inside the sandbox
+    fn get_app_name(&self, env: &HashMap<OsString, OsString>) -> Option<String>;
+    /// Returns what identifies this particular install of the app, used to tell apart nested
+    ///   sandboxes of the same kind
+    fn get_instance_name(&self, env: &HashMap<OsString, OsString>) -> Option<String> {
+        self.get_app_name(env)
+    }
+    /// Returns every directory belonging to the sandbox
+    fn get_locations(&self, env: &HashMap<OsString, OsString>) -> Locations;
+    /// Returns the variables the sandbox is known to set
+    fn get_catalog(&self) -> &'static [KnownVariable];
+}
+
+/// A snap package, detected by the SNAP variable snapd sets
+/// Since apps sometimes unset SNAP, running an executable from the same snap, the control group
+///   snapd puts the snap's apps in (snap.<instance>.<app>.*) and snapd's launchers are also taken
+///   into account
+#[derive(Debug)]
+pub struct Snap;
+
+/// Executables snapd runs when launching a snap app
+const SNAP_LAUNCHERS: &[&str] = &["snap-confine", "snap-exec"];
+
+/// Directories snapd mounts snaps in, depending on the distribution
+const MOUNT_DIRS: &[&str] = &["/snap", "/var/lib/snapd/snap"];
+
+impl Snap {
+    /// Returns the instance of the snap an executable belongs to, from its path such as
+    ///   /snap/<instance>/<revision>/... (/snap/bin holds links to the snap command rather than
+    ///   snap contents, so it doesn't count)
+    fn get_path_instance(path: &Path) -> Option<String> {
+        MOUNT_DIRS.iter().find_map(|dir| {
+            let mut components = path.strip_prefix(dir).ok()?.components();
+            let instance = components.next()?.as_os_str().to_str()?;
+            match (components.next(), components.next()) {
+                (Some(_), Some(_)) if instance != "bin" => Some(String::from(instance)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns the instance of the snap a control group path was created for by snapd, from
+    ///   the scope or service it names (snap.<instance>.<app>.*)
+    fn get_cgroup_instance(cgroup: &str) -> Option<String> {
+        cgroup.split('/').find_map(|name| {
+            if !name.ends_with(".scope") && !name.ends_with(".service") {
+                return None;
+            }
+            let (instance, _) = name.strip_prefix("snap.")?.split_once('.')?;
+            Some(String::from(instance))
+        })
+    }
+
+    /// Returns the instance of the snap a process is in, from its environment, or else from its
+    ///   executable or control group
+    fn get_process_instance(process: &Snapshot) -> Option<String> {
+        Snap.get_instance_name(&process.env)
+            .filter(|_| Snap.is_inside(&process.env))
+            .or_else(|| process.exe.as_deref().and_then(Snap::get_path_instance))
+            .or_else(|| {
+                process
+                    .cgroups
+                    .iter()
+                    .find_map(|cgroup| Snap::get_cgroup_instance(cgroup))
+            })
+    }
+}
+
+impl Sandbox for Snap {
+    fn get_name(&self) -> &'static str {
+        "snap"
+    }
+
+    fn is_inside(&self, env: &HashMap<OsString, OsString>) -> bool {
+        env.contains_key(OsStr::new("SNAP"))
+    }
+
+    /// Executables and control groups only count if they belong to the snap being escaped, which
+    ///   is the one the nearest visited process that tells is in (or else the process itself)
+    fn get_signal(&self, process: &Snapshot, visited: &[Snapshot]) -> Option<Signal> {
+        let current = visited
+            .iter()
+            .rev()
+            .chain(std::iter::once(process))
+            .find_map(Snap::get_process_instance);
+        let is_current = |instance: Option<String>| instance.is_some() && instance == current;
+        if self.is_inside(&process.env) {
+            Some(Signal::Environment)
+        } else if is_current(process.exe.as_deref().and_then(Snap::get_path_instance)) {
+            Some(Signal::Executable)
+        } else if process
+            .cgroups
+            .iter()
+            .any(|cgroup| is_current(Snap::get_cgroup_instance(cgroup)))
+        {
+            Some(Signal::Cgroup)
+        } else if SNAP_LAUNCHERS.iter().any(|launcher| {
+            process.comm == *launcher
+                || process
+                    .cmdline
+                    .first()
+                    .is_some_and(|argv0| Path::new(argv0).ends_with(launcher))
+        }) {
+            Some(Signal::Launcher)
+        } else {
+            None
+        }
+    }
+
+    fn get_root(&self, env: &HashMap<OsString, OsString>) -> Option<PathBuf> {
+        env.get(OsStr::new("SNAP")).map(PathBuf::from)
+    }
+
+    fn get_app_name(&self, env: &HashMap<OsString, OsString>) -> Option<String> {
+        env.get(OsStr::new("SNAP_NAME"))
+            .and_then(|name| name.to_str())
+            .map(String::from)
+    }
+
+    /// SNAP_INSTANCE_NAME includes the instance key of parallel installs, but is missing on old
+    ///   versions of snapd
+    fn get_instance_name(&self, env: &HashMap<OsString, OsString>) -> Option<String> {
+        env.get(OsStr::new("SNAP_INSTANCE_NAME"))
+            .and_then(|name| name.to_str())
+            .map(String::from)
+            .or_else(|| self.get_app_name(env))
+    }
+
+    fn get_locations(&self, env: &HashMap<OsString, OsString>) -> Locations {
+        Locations::from_snap_env(env)
+    }
+
+    fn get_catalog(&self) -> &'static [KnownVariable] {
+        variable::SNAP_CATALOG
+    }
+}
+
+/// An AppImage, detected by the APPDIR and APPIMAGE variables the AppImage runtime sets
+/// Either is enough, since AppRun scripts and apps sometimes unset one of them
+#[derive(Debug)]
+pub struct AppImage;
+
+impl Sandbox for AppImage {
+    fn get_name(&self) -> &'static str {
+        "AppImage"
+    }
+
+    fn is_inside(&self, env: &HashMap<OsString, OsString>) -> bool {
+        env.contains_key(OsStr::new("APPDIR")) || env.contains_key(OsStr::new("APPIMAGE"))
+    }
+
+    fn get_root(&self, env: &HashMap<OsString, OsString>) -> Option<PathBuf> {
+        env.get(OsStr::new("APPDIR")).map(PathBuf::from)
+    }
+
+    /// The name of the .AppImage file, without the extension
+    fn get_app_name(&self, env: &HashMap<OsString, OsString>) -> Option<String> {
+        env.get(OsStr::new("APPIMAGE"))
+            .and_then(|path| Path::new(path).file_stem())
+            .and_then(|name| name.to_str())
+            .map(String::from)
+    }
+
+    fn get_locations(&self, env: &HashMap<OsString, OsString>) -> Locations {
+        Locations::from_dirs(env.get(OsStr::new("APPDIR")).map(Path::new))
+    }
+
+    fn get_catalog(&self) -> &'static [KnownVariable] {
+        variable::APPIMAGE_CATALOG
+    }
+}
+
+/// A Flatpak app, detected by the FLATPAK_ID variable flatpak sets
+/// Processes outside a Flatpak can't be seen from inside it, so commands are escaped by running
+///   them on the host (see the flatpak module) instead of by walking the process tree
+#[derive(Debug)]
+pub struct Flatpak;
+
+impl Sandbox for Flatpak {
+    fn get_name(&self) -> &'static str {
+        "Flatpak"
+    }
+
+    fn is_inside(&self, env: &HashMap<OsString, OsString>) -> bool {
+        env.contains_key(OsStr::new("FLATPAK_ID"))
+    }
+
+    fn get_root(&self, _env: &HashMap<OsString, OsString>) -> Option<PathBuf> {
+        Some(PathBuf::from("/app"))
+    }
+
+    fn get_app_name(&self, env: &HashMap<OsString, OsString>) -> Option<String> {
+        env.get(OsStr::new("FLATPAK_ID"))
+            .and_then(|name| name.to_str())
+            .map(String::from)
+    }
+
+    /// Only /app, since /usr inside the sandbox is the runtime but on the host is the host's own
+    fn get_locations(&self, _env: &HashMap<OsString, OsString>) -> Locations {
+        Locations::from_dirs(Some(Path::new("/app")))
+    }
+
+    fn get_catalog(&self) -> &'static [KnownVariable] {
+        variable::FLATPAK_CATALOG
+    }
+}
+
+/// Picks the sandbox the given process is running in, if any
+/// Flatpak isn't included, since escaping it doesn't go through the process tree
+pub fn detect(process: &Snapshot) -> Option<Box<dyn Sandbox>> {
+    let sandboxes: Vec<Box<dyn Sandbox>> = vec![Box::new(Snap), Box::new(AppImage)];
+    sandboxes
+        .into_iter()
+        .find(|sandbox| sandbox.get_signal(process, &[]).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::process::mock::mock_env;
+    use super::*;
+
+    fn mock_snapshot(env: HashMap<OsString, OsString>) -> Snapshot {
+        Snapshot {
+            pid: 100,
+            start_time: 0,
+            comm: String::from("mock"),
+            exe: None,
+            cmdline: Vec::new(),
+            cgroups: Vec::new(),
+            inside: None,
+            snap_owned: false,
+            env: std::rc::Rc::new(env),
+        }
+    }
+
+    #[test]
+    fn detects_snap() {
+        let env = mock_env(vec![("SNAP", "/snap/foo/1"), ("SNAP_NAME", "foo")]);
+        let sandbox = detect(&mock_snapshot(env.clone())).expect("Failed to detect sandbox");
+        assert_eq!(sandbox.get_name(), "snap");
+        assert_eq!(sandbox.get_root(&env), Some(PathBuf::from("/snap/foo/1")));
+        assert_eq!(sandbox.get_app_name(&env), Some(String::from("foo")));
+        assert_eq!(sandbox.get_instance_name(&env), Some(String::from("foo")));
+        let env = mock_env(vec![
+            ("SNAP", "/snap/foo_beta/1"),
+            ("SNAP_NAME", "foo"),
+            ("SNAP_INSTANCE_NAME", "foo_beta"),
+        ]);
+        assert_eq!(Snap.get_instance_name(&env), Some(String::from("foo_beta")));
+    }
+
+    #[test]
+    fn detects_appimage() {
+        let env = mock_env(vec![
+            ("APPDIR", "/tmp/.mount_fooAbC"),
+            ("APPIMAGE", "/home/alice/Apps/foo.AppImage"),
+        ]);
+        let sandbox = detect(&mock_snapshot(env.clone())).expect("Failed to detect sandbox");
+        assert_eq!(sandbox.get_name(), "AppImage");
+        assert_eq!(
+            sandbox.get_root(&env),
+            Some(PathBuf::from("/tmp/.mount_fooAbC"))
+        );
+        assert_eq!(sandbox.get_app_name(&env), Some(String::from("foo")));
+        assert!(sandbox
+            .get_locations(&env)
+            .contains(OsStr::new("/tmp/.mount_fooAbC/usr/lib")));
+        let env = mock_env(vec![("APPIMAGE", "/home/alice/Apps/foo.AppImage")]);
+        let sandbox = detect(&mock_snapshot(env.clone())).expect("Failed to detect sandbox");
+        assert_eq!(sandbox.get_name(), "AppImage");
+        assert_eq!(sandbox.get_app_name(&env), Some(String::from("foo")));
+    }
+
+    #[test]
+    fn detects_flatpak() {
+        let env = mock_env(vec![("FLATPAK_ID", "org.example.Foo")]);
+        assert!(Flatpak.is_inside(&env));
+        assert_eq!(
+            Flatpak.get_app_name(&env),
+            Some(String::from("org.example.Foo"))
+        );
+        assert!(!Flatpak.get_locations(&env).contains(OsStr::new("/usr/bin")));
+        // Walking the process tree can't escape a Flatpak, so it is left to the flatpak module
+        assert!(detect(&mock_snapshot(env)).is_none());
+    }
+
+    #[test]
+    fn detects_no_sandbox() {
+        let env = mock_env(vec![("HOME", "/home/alice")]);
+        assert!(detect(&mock_snapshot(env)).is_none());
+    }
+
+    #[test]
+    fn detects_snap_without_environment() {
+        // The current process unset SNAP, but runs from the snap
+        let mut myself = mock_snapshot(HashMap::new());
+        myself.exe = Some(PathBuf::from("/snap/foo/2/bin/app"));
+        assert_eq!(Snap.get_signal(&myself, &[]), Some(Signal::Executable));
+        let visited = [myself];
+        let mut process = mock_snapshot(HashMap::new());
+        assert_eq!(Snap.get_signal(&process, &visited), None);
+        process.exe = Some(PathBuf::from("/snap/foo/1/usr/bin/foo"));
+        assert_eq!(
+            Snap.get_signal(&process, &visited),
+            Some(Signal::Executable)
+        );
+        process.exe = Some(PathBuf::from("/var/lib/snapd/snap/foo/1/usr/bin/foo"));
+        assert_eq!(
+            Snap.get_signal(&process, &visited),
+            Some(Signal::Executable)
+        );
+        // Only the current snap's executables and control groups count
+        process.exe = Some(PathBuf::from("/snap/bar/1/usr/bin/bar"));
+        assert_eq!(Snap.get_signal(&process, &visited), None);
+        process.exe = Some(PathBuf::from("/usr/bin/snap"));
+        process.cgroups = vec![String::from(
+            "/user.slice/user@1000.service/app.slice/snap.foo.foo-1a2b.scope",
+        )];
+        assert_eq!(Snap.get_signal(&process, &visited), Some(Signal::Cgroup));
+        let visited = [mock_snapshot(mock_env(vec![
+            ("SNAP", "/snap/bar/1"),
+            ("SNAP_NAME", "bar"),
+        ]))];
+        assert_eq!(Snap.get_signal(&process, &visited), None);
+        process.cgroups = vec![String::from(
+            "/user.slice/user@1000.service/app.slice/vte-spawn-1a2b.scope",
+        )];
+        process.comm = String::from("snap-exec");
+        assert_eq!(Snap.get_signal(&process, &visited), Some(Signal::Launcher));
+        process.comm = String::from("bash");
+        process.cmdline = vec![String::from("/usr/lib/snapd/snap-confine")];
+        assert_eq!(Snap.get_signal(&process, &visited), Some(Signal::Launcher));
+    }
+
+    #[test]
+    fn does_not_treat_snap_bin_as_inside() {
+        let mut process = mock_snapshot(HashMap::new());
+        process.exe = Some(PathBuf::from("/snap/bin/foo"));
+        assert_eq!(Snap.get_signal(&process, &[]), None);
+        process.exe = Some(PathBuf::from("/snap/foo"));
+        assert_eq!(Snap.get_signal(&process, &[]), None);
+    }
+}
@@ -0,0 +1,227 @@
+use std::os::unix::io::RawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+
+/// Signals that are passed on to the child instead of acting on this process
+pub const FORWARDED_SIGNALS: [libc::c_int; 8] = [
+    libc::SIGINT,
+    libc::SIGTERM,
+    libc::SIGHUP,
+    libc::SIGQUIT,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGWINCH,
+    libc::SIGCONT,
+];
+/// How long to wait for a signal before checking on the child anyway, in case SIGCHLD went to
+///   another thread
+const POLL_INTERVAL_NS: libc::c_long = 100_000_000;
+/// The terminal is only handed over if it is on stdin
+const TERMINAL_FD: RawFd = libc::STDIN_FILENO;
+
+fn signal_set(signals: &[libc::c_int]) -> libc::sigset_t {
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &signal in signals {
+            libc::sigaddset(&mut set, signal);
+        }
+        set
+    }
+}
+
+/// Returns if this process is in the foreground of the terminal on stdin, so has control of it
+fn controls_terminal() -> bool {
+    unsafe { libc::isatty(TERMINAL_FD) == 1 && libc::tcgetpgrp(TERMINAL_FD) == libc::getpgrp() }
+}
+
+/// Makes a process group the foreground of the terminal
+/// SIGTTOU has to be blocked, otherwise a background process doing this gets stopped
+fn give_terminal(process_group: libc::pid_t) {
+    unsafe {
+        libc::tcsetpgrp(TERMINAL_FD, process_group);
+    }
+}
+
+/// Stops this process the same way the child was stopped, so the shell sees the job as stopped,
+///   then hands the terminal back and resumes the child's process group once the shell continues it
+fn mirror_stop(child: libc::pid_t) {
+    give_terminal(unsafe { libc::getpgrp() });
+    unsafe {
+        libc::raise(libc::SIGSTOP);
+    }
+    if controls_terminal() {
+        give_terminal(child);
+    }
+    unsafe {
+        libc::kill(-child, libc::SIGCONT);
+    }
+}
+
+/// Runs a command as a child process and waits for it, forwarding FORWARDED_SIGNALS to it
+/// If this process controls the terminal, the child gets its own process group which is made the
+///   foreground, so keys like Ctrl-C and Ctrl-Z only reach the child. When the child is stopped
+///   this process stops too, and the terminal is taken back once the child exits.
+pub fn supervise(mut command: Command) -> std::io::Result<ExitStatus> {
+    let mut blocked = FORWARDED_SIGNALS.to_vec();
+    blocked.push(libc::SIGCHLD);
+    blocked.push(libc::SIGTTOU);
+    let blocked = signal_set(&blocked);
+    let forwarded = signal_set(&FORWARDED_SIGNALS);
+    let mut previous = signal_set(&[]);
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &blocked, &mut previous);
+    }
+    let terminal = controls_terminal();
+    if terminal {
+        command.process_group(0);
+    }
+    // Children inherit the signal mask, so the child restores it before running the command
+    // It also takes the terminal itself, so it can't try to use it before we hand it over
+    unsafe {
+        command.pre_exec(move || {
+            if terminal {
+                give_terminal(libc::getpid());
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+            Ok(())
+        });
+    }
+    let result = command.spawn().and_then(|child| {
+        let pid = child.id() as libc::pid_t;
+        if terminal {
+            give_terminal(pid);
+        }
+        let status = wait(pid, &forwarded, terminal);
+        if terminal {
+            give_terminal(unsafe { libc::getpgrp() });
+        }
+        status
+    });
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+    }
+    result
+}
+
+/// Waits for the child to exit, forwarding signals that arrive in the meantime
+fn wait(
+    child: libc::pid_t,
+    forwarded: &libc::sigset_t,
+    terminal: bool,
+) -> std::io::Result<ExitStatus> {
+    let all = {
+        let mut set = *forwarded;
+        unsafe {
+            libc::sigaddset(&mut set, libc::SIGCHLD);
+        }
+        set
+    };
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: POLL_INTERVAL_NS,
+    };
+    loop {
+        let mut status = 0;
+        let options = if terminal {
+            libc::WNOHANG | libc::WUNTRACED
+        } else {
+            libc::WNOHANG
+        };
+        match unsafe { libc::waitpid(child, &mut status, options) } {
+            0 => (),
+            pid if pid == child && libc::WIFSTOPPED(status) => mirror_stop(child),
+            pid if pid == child => return Ok(ExitStatus::from_raw(status)),
+            _ => {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+        }
+        let mut info = unsafe { std::mem::zeroed() };
+        let signal = unsafe { libc::sigtimedwait(&all, &mut info, &timeout) };
+        if signal > 0 && signal != libc::SIGCHLD {
+            // With its own process group, the whole group gets it just like keys on the terminal
+            let target = if terminal { -child } else { child };
+            unsafe {
+                libc::kill(target, signal);
+            }
+        }
+    }
+}
+
+/// Ends this process with the same signal that ended the child, so whatever is waiting on it
+///   (such as a shell deciding whether to stop a script on Ctrl-C) sees the same result
+/// Core dumps are disabled first, since the crash was not ours. Returns if the signal does not
+///   end processes by default.
+pub fn mirror_signal(signal: libc::c_int) {
+    let no_core = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+        libc::signal(signal, libc::SIG_DFL);
+        let set = signal_set(&[signal]);
+        libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+        libc::raise(signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn passes_exit_code_through() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 3"]);
+        let status = supervise(command).expect("Failed to spawn");
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn reports_signal_child_died_from() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "kill -TERM $$"]);
+        let status = supervise(command).expect("Failed to spawn");
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    }
+
+    #[test]
+    fn forwards_signals_to_child() {
+        let ready =
+            std::env::temp_dir().join(format!("snap-out-test-spawn-ready-{}", std::process::id()));
+        let _ = std::fs::remove_file(&ready);
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "trap 'exit 42' USR1; touch \"$0\"; while true; do sleep 0.01; done",
+        ]);
+        command.arg(&ready);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let supervisor = std::thread::spawn(move || {
+            sender.send(unsafe { libc::pthread_self() }).unwrap();
+            supervise(command).expect("Failed to spawn")
+        });
+        let thread = receiver.recv().unwrap();
+        // The child only sets up its trap after the supervisor has blocked the signal, so it can
+        //   be sent to the supervisor's thread without killing the test process
+        let start = Instant::now();
+        while !ready.exists() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "Child never started"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        unsafe {
+            libc::pthread_kill(thread, libc::SIGUSR1);
+        }
+        let status = supervisor.join().unwrap();
+        std::fs::remove_file(&ready).expect("Failed to clean up");
+        assert_eq!(status.code(), Some(42));
+    }
+}
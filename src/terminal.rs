@@ -0,0 +1,264 @@
+use super::command;
+use super::open;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the terminal emulator to use, before any other
+pub const TERMINAL_ENV_VAR: &str = "SNAP_OUT_TERMINAL";
+/// Terminal emulators that are looked for on PATH, after TERMINAL_ENV_VAR and $TERMINAL
+/// x-terminal-emulator is the one chosen through Debian's alternatives system
+pub const FALLBACKS: [&str; 4] = ["x-terminal-emulator", "gnome-terminal", "konsole", "xterm"];
+
+/// How an emulator is told which directory to start in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DirectoryArg {
+    /// The directory is appended to the option, as in --working-directory=DIR
+    Joined(&'static str),
+    /// The directory is the next argument
+    Separate(&'static str),
+    /// It has no option, and starts in its own working directory
+    None,
+}
+
+/// The arguments an emulator takes
+struct Syntax {
+    name: &'static str,
+    /// Comes before anything else, such as a subcommand
+    prefix: &'static [&'static str],
+    directory: DirectoryArg,
+    /// Comes before the command and its arguments
+    command: &'static [&'static str],
+}
+
+/// Emulators that don't take the command after -e, or that can be told the directory (which is
+///   needed for ones like gnome-terminal whose windows are opened by a server process)
+const SYNTAXES: [Syntax; 8] = [
+    Syntax {
+        name: "gnome-terminal",
+        prefix: &[],
+        directory: DirectoryArg::Joined("--working-directory="),
+        command: &["--"],
+    },
+    Syntax {
+        name: "konsole",
+        prefix: &[],
+        directory: DirectoryArg::Separate("--workdir"),
+        command: &["-e"],
+    },
+    Syntax {
+        name: "xfce4-terminal",
+        prefix: &[],
+        directory: DirectoryArg::Joined("--working-directory="),
+        command: &["-x"],
+    },
+    Syntax {
+        name: "mate-terminal",
+        prefix: &[],
+        directory: DirectoryArg::Joined("--working-directory="),
+        command: &["-x"],
+    },
+    Syntax {
+        name: "kitty",
+        prefix: &[],
+        directory: DirectoryArg::Separate("--directory"),
+        command: &[],
+    },
+    Syntax {
+        name: "alacritty",
+        prefix: &[],
+        directory: DirectoryArg::Separate("--working-directory"),
+        command: &["-e"],
+    },
+    Syntax {
+        name: "foot",
+        prefix: &[],
+        directory: DirectoryArg::Joined("--working-directory="),
+        command: &[],
+    },
+    Syntax {
+        name: "wezterm",
+        prefix: &["start"],
+        directory: DirectoryArg::Separate("--cwd"),
+        command: &["--"],
+    },
+];
+/// Used for any other emulator, since -e is what xterm takes and most others copy it
+const DEFAULT_SYNTAX: Syntax = Syntax {
+    name: "xterm",
+    prefix: &[],
+    directory: DirectoryArg::None,
+    command: &["-e"],
+};
+
+/// Returns the path of an executable, which is either a path already or looked up on PATH
+fn find_executable(name: &OsStr, path: Option<&OsStr>) -> Option<PathBuf> {
+    if name.is_empty() {
+        return None;
+    }
+    let candidate = Path::new(name);
+    if candidate.components().count() > 1 {
+        return Some(candidate.to_owned()).filter(|candidate| open::is_executable(candidate));
+    }
+    open::find_in_path(&name.to_string_lossy(), path?)
+}
+
+/// Returns the emulator set in TERMINAL_ENV_VAR, or else $TERMINAL
+pub fn get_configured(env: &HashMap<OsString, OsString>) -> Option<OsString> {
+    [TERMINAL_ENV_VAR, "TERMINAL"]
+        .iter()
+        .filter_map(|&var| env.get(OsStr::new(var)))
+        .find(|name| !name.is_empty())
+        .cloned()
+}
+
+/// Finds the terminal emulator to use
+/// The configured one comes first, then FALLBACKS are looked for on PATH (which should be the
+///   cleaned one, so the host's emulator is found)
+pub fn find(path: Option<&OsStr>, env: &HashMap<OsString, OsString>) -> Option<PathBuf> {
+    let fallbacks = FALLBACKS.iter().map(OsString::from);
+    get_configured(env)
+        .into_iter()
+        .chain(fallbacks)
+        .find_map(|name| find_executable(&name, path))
+}
+
+/// Picks the syntax of an emulator from its file name, following symlinks such as
+///   x-terminal-emulator to the emulator they stand for
+fn get_syntax(terminal: &Path) -> &'static Syntax {
+    let resolved = std::fs::canonicalize(terminal).unwrap_or_else(|_| terminal.to_owned());
+    [terminal, resolved.as_path()]
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str())
+        .find_map(|name| SYNTAXES.iter().find(|syntax| syntax.name == name))
+        .unwrap_or(&DEFAULT_SYNTAX)
+}
+
+/// Returns the arguments that make an emulator start in a directory and run a command (or the
+///   user's shell if the command is empty)
+pub fn get_args(terminal: &Path, directory: Option<&Path>, command: &[String]) -> Vec<OsString> {
+    let syntax = get_syntax(terminal);
+    let mut args: Vec<OsString> = syntax.prefix.iter().map(OsString::from).collect();
+    match (syntax.directory, directory) {
+        (DirectoryArg::Joined(option), Some(directory)) => {
+            let mut arg = OsString::from(option);
+            arg.push(directory);
+            args.push(arg);
+        }
+        (DirectoryArg::Separate(option), Some(directory)) => {
+            args.push(OsString::from(option));
+            args.push(directory.as_os_str().to_owned());
+        }
+        _ => (),
+    }
+    if !command.is_empty() {
+        args.extend(syntax.command.iter().map(OsString::from));
+        args.extend(command.iter().map(OsString::from));
+    }
+    args
+}
+
+/// Opens the terminal emulator in the current directory, running a command in it if one is
+///   given, detached so it outlives the snap app
+pub fn run(
+    command: &[String],
+    vars: &[(OsString, Option<OsString>)],
+    env: &HashMap<OsString, OsString>,
+) -> i32 {
+    let path = open::get_changed_value("PATH", vars, env);
+    let terminal = match find(path.as_deref(), env) {
+        Some(terminal) => terminal,
+        None => {
+            eprintln!(
+                "{}: Could not find a terminal emulator, set {} to the one to use",
+                env!("CARGO_PKG_NAME"),
+                TERMINAL_ENV_VAR
+            );
+            return command::EXIT_NOT_FOUND;
+        }
+    };
+    let directory = std::env::current_dir().ok();
+    let args = get_args(&terminal, directory.as_deref(), command);
+    let mode = command::Mode::Detach {
+        log: None,
+        pid_file: None,
+    };
+    command::run(&mode, &terminal.to_string_lossy(), None, args, vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn get_args_str(terminal: &str, directory: Option<&str>, command: &[&str]) -> Vec<String> {
+        let command: Vec<String> = command.iter().map(|arg| arg.to_string()).collect();
+        get_args(Path::new(terminal), directory.map(Path::new), &command)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn uses_syntax_of_each_emulator() {
+        assert_eq!(
+            get_args_str("/usr/bin/gnome-terminal", Some("/home/a"), &["vim", "-R"]),
+            vec!["--working-directory=/home/a", "--", "vim", "-R"]
+        );
+        assert_eq!(
+            get_args_str("/usr/bin/konsole", Some("/home/a"), &["vim"]),
+            vec!["--workdir", "/home/a", "-e", "vim"]
+        );
+        assert_eq!(
+            get_args_str("kitty", Some("/home/a"), &["vim"]),
+            vec!["--directory", "/home/a", "vim"]
+        );
+        assert_eq!(
+            get_args_str("wezterm", Some("/home/a"), &["vim"]),
+            vec!["start", "--cwd", "/home/a", "--", "vim"]
+        );
+        assert_eq!(get_args_str("wezterm", None, &[]), vec!["start"]);
+        assert_eq!(
+            get_args_str("/usr/bin/xterm", Some("/home/a"), &["vim"]),
+            vec!["-e", "vim"]
+        );
+        assert_eq!(
+            get_args_str("/opt/unknown-term", Some("/home/a"), &[]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn finds_emulator() {
+        let dir =
+            std::env::temp_dir().join(format!("snap-out-terminal-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        for name in ["xterm", "konsole", "my-term"] {
+            std::fs::write(dir.join(name), "").expect("Failed to write emulator");
+            std::fs::set_permissions(dir.join(name), std::fs::Permissions::from_mode(0o755))
+                .expect("Failed to make emulator executable");
+        }
+        std::os::unix::fs::symlink(dir.join("konsole"), dir.join("x-terminal-emulator"))
+            .expect("Failed to link emulator");
+        let path = dir.clone().into_os_string();
+        let mut env = HashMap::new();
+        let found = find(Some(&path), &env).expect("Should have found an emulator");
+        assert_eq!(found, dir.join("x-terminal-emulator"));
+        assert_eq!(
+            get_args_str(found.to_str().unwrap(), Some("/home/a"), &[]),
+            vec!["--workdir", "/home/a"]
+        );
+        env.insert(OsString::from("TERMINAL"), OsString::from("konsole"));
+        assert_eq!(find(Some(&path), &env), Some(dir.join("konsole")));
+        env.insert(
+            OsString::from(TERMINAL_ENV_VAR),
+            dir.join("my-term").into_os_string(),
+        );
+        assert_eq!(find(Some(&path), &env), Some(dir.join("my-term")));
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+        assert_eq!(
+            find(Some(OsStr::new("/nonexistent")), &HashMap::new()),
+            None
+        );
+    }
+}
@@ -1,4 +1,568 @@
-use std::ffi::OsString;
+use super::environments::Snapshot;
+use super::locations::Locations;
+use super::rules::Policy;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::rc::Rc;
+
+/// A variable that is known to be used by snaps
+#[derive(Debug, PartialEq)]
+pub struct KnownVariable {
+    pub name: &'static str,
+    /// The bytes that separate entries, or None if it holds a single opaque value
+    /// Entries are split on any of them and joined with the first. The snap generally prepends its
+    ///   own entries to lists, so they are merged entry by entry
+    pub separator: Option<&'static [u8]>,
+    /// If the variable is only ever set by the sandbox (snapd, snapcraft extensions, launchers, the
+    ///   AppImage runtime...), in which case a single value that changed after the boundary is
+    ///   taken to have been changed by a launcher rather than by the app
+    pub sandbox_only: bool,
+    pub description: &'static str,
+}
+
+/// Common lists that sandboxes add their own entries to
+pub const COMMON_CATALOG: &[KnownVariable] = &[
+    KnownVariable {
+        name: "PATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for executables",
+    },
+    KnownVariable {
+        name: "LD_LIBRARY_PATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for shared libraries before the system ones",
+    },
+    KnownVariable {
+        name: "XDG_DATA_DIRS",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description:
+            "Directories searched for shared data such as icons, schemas and .desktop files",
+    },
+    KnownVariable {
+        name: "XDG_CONFIG_DIRS",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for system-wide configuration",
+    },
+    KnownVariable {
+        name: "GST_PLUGIN_PATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for GStreamer plugins",
+    },
+    KnownVariable {
+        name: "GST_PLUGIN_SYSTEM_PATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for system GStreamer plugins",
+    },
+    KnownVariable {
+        name: "PYTHONPATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for Python modules",
+    },
+    KnownVariable {
+        name: "PERL5LIB",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for Perl modules",
+    },
+    KnownVariable {
+        name: "GI_TYPELIB_PATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for GObject introspection typelibs",
+    },
+    KnownVariable {
+        name: "PKG_CONFIG_PATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for pkg-config files",
+    },
+    KnownVariable {
+        name: "MANPATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for man pages",
+    },
+    KnownVariable {
+        name: "INFOPATH",
+        separator: Some(b":"),
+        sandbox_only: false,
+        description: "Directories searched for info pages",
+    },
+];
+
+/// Variables set by snapd, the snapcraft extensions (gnome, kde-neon), desktop-launch,
+///   snapcraft-runner and snapcraft-preload
+pub const SNAP_CATALOG: &[KnownVariable] = &[
+    KnownVariable {
+        name: "GTK_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "GTK module directories, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "GTK_EXE_PREFIX",
+        separator: None,
+        sandbox_only: true,
+        description: "GTK installation prefix, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "GTK_DATA_PREFIX",
+        separator: None,
+        sandbox_only: true,
+        description: "GTK data prefix, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "GTK_IM_MODULE_FILE",
+        separator: None,
+        sandbox_only: true,
+        description: "GTK input method module cache, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "GDK_PIXBUF_MODULE_FILE",
+        separator: None,
+        sandbox_only: true,
+        description: "gdk-pixbuf loader cache, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "GDK_PIXBUF_MODULEDIR",
+        separator: None,
+        sandbox_only: true,
+        description: "gdk-pixbuf loader directory, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "GIO_MODULE_DIR",
+        separator: None,
+        sandbox_only: true,
+        description: "GIO module directory, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "GSETTINGS_SCHEMA_DIR",
+        separator: None,
+        sandbox_only: true,
+        description: "GSettings schema directory, set by the gnome extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "LOCPATH",
+        separator: None,
+        sandbox_only: true,
+        description: "Compiled locale directory, set by desktop-launch",
+    },
+    KnownVariable {
+        name: "FONTCONFIG_PATH",
+        separator: None,
+        sandbox_only: true,
+        description: "Fontconfig configuration directory, set by desktop-launch",
+    },
+    KnownVariable {
+        name: "FONTCONFIG_FILE",
+        separator: None,
+        sandbox_only: true,
+        description: "Fontconfig configuration file, set by desktop-launch",
+    },
+    KnownVariable {
+        name: "QT_PLUGIN_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Qt plugin directories, set by the kde-neon extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "QML2_IMPORT_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "QML module directories, set by the kde-neon extension and desktop-launch",
+    },
+    KnownVariable {
+        name: "QT_QPA_PLATFORM_PLUGIN_PATH",
+        separator: None,
+        sandbox_only: true,
+        description: "Qt platform plugin directory, set by the kde-neon extension",
+    },
+    KnownVariable {
+        name: "LIBGL_DRIVERS_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Mesa DRI driver directories, set by desktop-launch",
+    },
+    KnownVariable {
+        name: "__EGL_VENDOR_LIBRARY_DIRS",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "GLVND EGL vendor directories, set by desktop-launch",
+    },
+    KnownVariable {
+        name: "TERMINFO_DIRS",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Terminfo database directories, set by snapcraft-runner and desktop-launch",
+    },
+    KnownVariable {
+        name: "LD_PRELOAD",
+        separator: Some(b" :"),
+        sandbox_only: true,
+        description: "Libraries loaded before all others, set by snapcraft-preload",
+    },
+    KnownVariable {
+        name: "SNAPCRAFT_PRELOAD",
+        separator: None,
+        sandbox_only: true,
+        description: "Location of snapcraft-preload's files",
+    },
+    KnownVariable {
+        name: "SNAP_LAUNCHER_ARCH_TRIPLET",
+        separator: None,
+        sandbox_only: true,
+        description: "Architecture triplet, set by the desktop launchers",
+    },
+    KnownVariable {
+        name: "SNAP_DESKTOP_RUNTIME",
+        separator: None,
+        sandbox_only: true,
+        description: "Location of the desktop runtime, set by the gnome and kde-neon extensions",
+    },
+    KnownVariable {
+        name: "SNAP",
+        separator: None,
+        sandbox_only: true,
+        description: "Where the snap is mounted, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_ARCH",
+        separator: None,
+        sandbox_only: true,
+        description: "Architecture of the snap, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_COMMON",
+        separator: None,
+        sandbox_only: true,
+        description: "System data shared between revisions, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_CONTEXT",
+        separator: None,
+        sandbox_only: true,
+        description: "Context for snapctl, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_COOKIE",
+        separator: None,
+        sandbox_only: true,
+        description: "Context for snapctl, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_DATA",
+        separator: None,
+        sandbox_only: true,
+        description: "System data of this revision, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_EUID",
+        separator: None,
+        sandbox_only: true,
+        description: "Effective user ID, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_INSTANCE_KEY",
+        separator: None,
+        sandbox_only: true,
+        description: "Instance key of a parallel install, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_INSTANCE_NAME",
+        separator: None,
+        sandbox_only: true,
+        description: "Name of the snap including the instance key, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_LIBRARY_PATH",
+        separator: None,
+        sandbox_only: true,
+        description: "Library directories added by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_NAME",
+        separator: None,
+        sandbox_only: true,
+        description: "Name of the snap, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_REAL_HOME",
+        separator: None,
+        sandbox_only: true,
+        description: "The user's real home directory, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_REEXEC",
+        separator: None,
+        sandbox_only: true,
+        description: "Whether snapd re-executed itself, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_REVISION",
+        separator: None,
+        sandbox_only: true,
+        description: "Revision of the snap, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_SAVE_DATA",
+        separator: None,
+        sandbox_only: true,
+        description: "Data included in snapshots, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_UID",
+        separator: None,
+        sandbox_only: true,
+        description: "User ID, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_USER_COMMON",
+        separator: None,
+        sandbox_only: true,
+        description: "User data shared between revisions, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_USER_DATA",
+        separator: None,
+        sandbox_only: true,
+        description: "User data of this revision, set by snapd",
+    },
+    KnownVariable {
+        name: "SNAP_VERSION",
+        separator: None,
+        sandbox_only: true,
+        description: "Version of the snap, set by snapd",
+    },
+];
+
+/// Variables set by the AppImage runtime, AppRun scripts and the linuxdeploy plugins
+pub const APPIMAGE_CATALOG: &[KnownVariable] = &[
+    KnownVariable {
+        name: "APPDIR",
+        separator: None,
+        sandbox_only: true,
+        description: "Where the AppImage is mounted, set by the AppImage runtime",
+    },
+    KnownVariable {
+        name: "APPIMAGE",
+        separator: None,
+        sandbox_only: true,
+        description: "Path of the AppImage file, set by the AppImage runtime",
+    },
+    KnownVariable {
+        name: "ARGV0",
+        separator: None,
+        sandbox_only: true,
+        description: "Name the AppImage was invoked as, set by the AppImage runtime",
+    },
+    KnownVariable {
+        name: "OWD",
+        separator: None,
+        sandbox_only: true,
+        description: "Working directory the AppImage was started from, set by the AppImage runtime",
+    },
+    KnownVariable {
+        name: "GTK_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "GTK module directories, set by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "GTK_EXE_PREFIX",
+        separator: None,
+        sandbox_only: true,
+        description: "GTK installation prefix, set by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "GTK_DATA_PREFIX",
+        separator: None,
+        sandbox_only: true,
+        description: "GTK data prefix, set by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "GTK_IM_MODULE_FILE",
+        separator: None,
+        sandbox_only: true,
+        description: "GTK input method module cache, set by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "GDK_PIXBUF_MODULE_FILE",
+        separator: None,
+        sandbox_only: true,
+        description: "gdk-pixbuf loader cache, set by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "GDK_BACKEND",
+        separator: None,
+        sandbox_only: true,
+        description: "GDK backend, forced by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "GIO_MODULE_DIR",
+        separator: None,
+        sandbox_only: true,
+        description: "GIO module directory, set by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "GSETTINGS_SCHEMA_DIR",
+        separator: None,
+        sandbox_only: true,
+        description: "GSettings schema directory, set by the linuxdeploy gtk plugin",
+    },
+    KnownVariable {
+        name: "QT_PLUGIN_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Qt plugin directories, set by AppRun scripts",
+    },
+    KnownVariable {
+        name: "QML2_IMPORT_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "QML module directories, set by AppRun scripts",
+    },
+    KnownVariable {
+        name: "PYTHONHOME",
+        separator: None,
+        sandbox_only: true,
+        description: "Python installation prefix, set by AppRun scripts",
+    },
+    KnownVariable {
+        name: "PERLLIB",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Directories searched for Perl modules, set by AppRun scripts",
+    },
+];
+
+/// Variables set by flatpak and the Flatpak runtimes
+pub const FLATPAK_CATALOG: &[KnownVariable] = &[
+    KnownVariable {
+        name: "FLATPAK_ID",
+        separator: None,
+        sandbox_only: true,
+        description: "ID of the Flatpak app, set by flatpak",
+    },
+    KnownVariable {
+        name: "FLATPAK_ARCH",
+        separator: None,
+        sandbox_only: true,
+        description: "Architecture of the Flatpak app, set by flatpak",
+    },
+    KnownVariable {
+        name: "FLATPAK_SANDBOX_DIR",
+        separator: None,
+        sandbox_only: true,
+        description: "Per-app directory shared with the host, set by flatpak",
+    },
+    KnownVariable {
+        name: "container",
+        separator: None,
+        sandbox_only: true,
+        description: "Kind of container, set to flatpak by flatpak",
+    },
+    KnownVariable {
+        name: "XDG_CONFIG_HOME",
+        separator: None,
+        sandbox_only: true,
+        description: "Redirected to the app's directory in ~/.var/app by flatpak",
+    },
+    KnownVariable {
+        name: "XDG_DATA_HOME",
+        separator: None,
+        sandbox_only: true,
+        description: "Redirected to the app's directory in ~/.var/app by flatpak",
+    },
+    KnownVariable {
+        name: "XDG_CACHE_HOME",
+        separator: None,
+        sandbox_only: true,
+        description: "Redirected to the app's directory in ~/.var/app by flatpak",
+    },
+    KnownVariable {
+        name: "XDG_STATE_HOME",
+        separator: None,
+        sandbox_only: true,
+        description: "Redirected to the app's directory in ~/.var/app by flatpak",
+    },
+    KnownVariable {
+        name: "PYTHONUSERBASE",
+        separator: None,
+        sandbox_only: true,
+        description: "Redirected to the app's directory in ~/.var/app by the runtime",
+    },
+    KnownVariable {
+        name: "GIO_EXTRA_MODULES",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Extra GIO module directories, set by the runtime",
+    },
+    KnownVariable {
+        name: "GTK_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "GTK module directories, set by the runtime",
+    },
+    KnownVariable {
+        name: "ACLOCAL_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Directories searched for autoconf macros, set by the SDK",
+    },
+    KnownVariable {
+        name: "C_INCLUDE_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Directories searched for C headers, set by the SDK",
+    },
+    KnownVariable {
+        name: "CPLUS_INCLUDE_PATH",
+        separator: Some(b":"),
+        sandbox_only: true,
+        description: "Directories searched for C++ headers, set by the SDK",
+    },
+];
+
+/// Returns what is known about the given variable, looking in the given sandbox specific catalog
+///   before the common one
+pub fn lookup(name: &OsStr, catalog: &'static [KnownVariable]) -> Option<&'static KnownVariable> {
+    catalog
+        .iter()
+        .chain(COMMON_CATALOG.iter())
+        .find(|known| OsStr::new(known.name) == name)
+}
+
+/// Returns if a value, or any entry of it if the variable is a list, points into the locations
+pub fn points_into(value: &OsStr, known: Option<&KnownVariable>, locations: &Locations) -> bool {
+    match known.and_then(|known| known.separator) {
+        Some(separator) => split_list(value, separator)
+            .into_iter()
+            .any(|entry| locations.contains(OsStr::from_bytes(entry))),
+        None => locations.contains(value),
+    }
+}
+
+fn split_list<'a>(value: &'a OsStr, separator: &[u8]) -> Vec<&'a [u8]> {
+    if value.is_empty() {
+        Vec::new()
+    } else {
+        value.as_bytes().split(|b| separator.contains(b)).collect()
+    }
+}
+
+/// A value a variable was given, and the process in which it first appeared
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub process: Rc<Snapshot>,
+    pub value: Option<OsString>,
+}
 
 /// The multiple values a single environment variable has held
 /// See environments for what each means
@@ -7,6 +571,10 @@ pub struct Variable {
     external: Option<OsString>,
     snap: Option<OsString>,
     myself: Option<OsString>,
+    separator: Option<&'static [u8]>,
+    sandbox_only: bool,
+    policy: Policy,
+    history: Vec<Change>,
 }
 
 impl Variable {
@@ -19,17 +587,133 @@ impl Variable {
             external,
             snap,
             myself,
+            separator: None,
+            sandbox_only: false,
+            policy: Policy::Auto,
+            history: Vec::new(),
+        }
+    }
+
+    /// Applies what the catalog knows about the variable
+    pub fn with_known(mut self, known: Option<&KnownVariable>) -> Self {
+        if let Some(known) = known {
+            self.separator = known.separator;
+            self.sandbox_only = known.sandbox_only;
         }
+        self
+    }
+
+    /// Records every value the variable held between the process that launched the snap and
+    ///   this one
+    pub fn with_history(mut self, history: Vec<Change>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Overrides how the value the child process gets is decided
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
     }
 
     /// Returns the value the child process should have
     /// Attempts to remove the modifications to the environment made by the snap without effecting
     ///   the changes that were made after
     fn get_child_value(&self) -> Option<OsString> {
-        if self.myself != self.snap {
+        if self.myself == self.snap {
+            self.external.clone()
+        } else if let Some(separator) = self.separator {
+            let myself = self.myself.as_ref()?;
+            self.get_merged_list(separator, myself)
+        } else if self.sandbox_only {
+            // Launchers inside the snap set these after the boundary, which the comparison can't
+            //   tell apart from us setting them, but they are never meant to leave the snap
+            self.external.clone()
+        } else {
             self.myself.clone()
+        }
+    }
+
+    /// Removes the entries the snap added from our own value of a list variable, and puts back the
+    ///   ones it removed (a Flatpak replaces lists outright rather than adding to them)
+    /// Entries that came from outside the snap are put back in their external order, and entries
+    ///   added after the snap (by us or the user) follow the same external entry they follow in ours
+    fn get_merged_list(&self, separator: &[u8], myself: &OsStr) -> Option<OsString> {
+        let empty = OsString::new();
+        let external = split_list(self.external.as_ref().unwrap_or(&empty), separator);
+        let snap = split_list(self.snap.as_ref().unwrap_or(&empty), separator);
+        let myself = split_list(myself, separator);
+        // Entries added after the snap, along with the external entry they follow (if any)
+        let mut added: Vec<(Option<&[u8]>, &[u8])> = Vec::new();
+        let mut previous = None;
+        for &entry in &myself {
+            if external.contains(&entry) {
+                previous = Some(entry);
+            } else if !snap.contains(&entry) {
+                added.push((previous, entry));
+            }
+        }
+        let (leading, mut added): (Vec<_>, Vec<_>) =
+            added.into_iter().partition(|(after, _)| after.is_none());
+        let mut entries: Vec<&[u8]> = leading.into_iter().map(|(_, entry)| entry).collect();
+        // External entries the snap kept but that were removed after it stay removed
+        for entry in external
+            .into_iter()
+            .filter(|entry| myself.contains(entry) || !snap.contains(entry))
+        {
+            entries.push(entry);
+            let (following, rest): (Vec<_>, Vec<_>) = added
+                .into_iter()
+                .partition(|(after, _)| *after == Some(entry));
+            entries.extend(following.into_iter().map(|(_, entry)| entry));
+            added = rest;
+        }
+        self.join_list(entries, separator)
+    }
+
+    /// Joins list entries back into a value
+    /// An empty list becomes unset if the variable was not set outside the snap
+    fn join_list(&self, entries: Vec<&[u8]>, separator: &[u8]) -> Option<OsString> {
+        if entries.is_empty() && self.external.is_none() {
+            None
         } else {
-            self.external.clone()
+            Some(OsString::from_vec(entries.join(&separator[..1])))
+        }
+    }
+
+    /// Removes anything pointing inside the snap from the value the child process would get
+    /// This catches references the snapshot comparison misses, such as values set by a wrapper
+    ///   script or entries left over from a previous revision
+    fn get_clean_value(&self, locations: &Locations) -> Option<OsString> {
+        let value = self.get_child_value()?;
+        if let Some(separator) = self.separator {
+            let outside_snap = |value| -> Vec<&[u8]> {
+                split_list(value, separator)
+                    .into_iter()
+                    .filter(|entry| !locations.contains(OsStr::from_bytes(entry)))
+                    .collect()
+            };
+            let count = split_list(&value, separator).len();
+            let entries = outside_snap(&value);
+            if entries.len() == count {
+                Some(value)
+            } else if entries.is_empty() {
+                // An empty list (such as PATH="") is no use to the child, so fall back to the
+                //   external value, or leave the variable unset if there is none
+                let entries = outside_snap(self.external.as_ref()?);
+                if entries.is_empty() {
+                    None
+                } else {
+                    Some(OsString::from_vec(entries.join(&separator[..1])))
+                }
+            } else {
+                self.join_list(entries, separator)
+            }
+        } else if locations.contains(&value) {
+            self.external
+                .clone()
+                .filter(|external| !locations.contains(external))
+        } else {
+            Some(value)
         }
     }
 
@@ -45,8 +729,69 @@ impl Variable {
     /// Will return None if the variable can be left as-is
     /// Will return Some(None) if the variable needs to be cleared
     /// Will return Some(Some(...)) if the variable needs to be set
-    pub fn get_required_change(&self) -> Option<Option<OsString>> {
-        self.get_change_to(self.get_child_value())
+    pub fn get_required_change(&self, locations: &Locations) -> Option<Option<OsString>> {
+        self.get_change_to(self.get_result(locations))
+    }
+
+    /// Returns the value the child process will get once policies are applied
+    pub fn get_result(&self, locations: &Locations) -> Option<OsString> {
+        match self.policy {
+            Policy::Auto => self.get_clean_value(locations),
+            Policy::Keep => self.myself.clone(),
+            Policy::Unset => None,
+            Policy::Restore => self.external.clone(),
+        }
+    }
+
+    /// Returns a short human readable description of why the variable gets the result it does
+    pub fn get_reason(&self, locations: &Locations) -> &'static str {
+        match self.policy {
+            Policy::Auto => (),
+            Policy::Keep => return "kept by rule",
+            Policy::Unset => return "cleared by rule",
+            Policy::Restore => return "restored by rule",
+        }
+        let child = self.get_child_value();
+        let clean = self.get_clean_value(locations);
+        if child != clean {
+            return match clean {
+                None => "points into snap, cleared",
+                Some(_) if self.separator.is_some() => "points into snap, entries removed",
+                Some(_) => "points into snap, restored",
+            };
+        }
+        if self.myself == self.snap {
+            match (&self.external, &self.snap) {
+                (external, snap) if external == snap => "unchanged",
+                (None, _) => "set by snap, cleared",
+                (_, None) => "cleared by snap, restored",
+                _ => "changed by snap, restored",
+            }
+        } else if self.sandbox_only && self.separator.is_none() {
+            "set by snap launcher, restored"
+        } else if self.myself.is_none() {
+            "cleared after snap, kept"
+        } else if child != self.myself {
+            "changed after snap, snap entries removed"
+        } else {
+            "changed after snap, kept"
+        }
+    }
+
+    pub fn get_external(&self) -> Option<&OsStr> {
+        self.external.as_deref()
+    }
+
+    pub fn get_snap(&self) -> Option<&OsStr> {
+        self.snap.as_deref()
+    }
+
+    pub fn get_myself(&self) -> Option<&OsStr> {
+        self.myself.as_deref()
+    }
+
+    pub fn get_history(&self) -> &[Change] {
+        &self.history
     }
 }
 
@@ -54,6 +799,202 @@ impl Variable {
 mod tests {
     use super::*;
 
+    fn list(external: Option<&str>, snap: Option<&str>, myself: Option<&str>) -> Variable {
+        let mut var = Variable::new(
+            external.map(OsString::from),
+            snap.map(OsString::from),
+            myself.map(OsString::from),
+        );
+        var.separator = Some(b":");
+        var
+    }
+
+    #[test]
+    fn looks_up_known_variables() {
+        assert_eq!(
+            lookup(OsStr::new("PATH"), SNAP_CATALOG).unwrap().separator,
+            Some(&b":"[..])
+        );
+        assert_eq!(
+            lookup(OsStr::new("XDG_DATA_DIRS"), SNAP_CATALOG)
+                .unwrap()
+                .separator,
+            Some(&b":"[..])
+        );
+        assert!(
+            lookup(OsStr::new("GDK_PIXBUF_MODULE_FILE"), SNAP_CATALOG)
+                .unwrap()
+                .sandbox_only
+        );
+        assert!(
+            !lookup(OsStr::new("PATH"), SNAP_CATALOG)
+                .unwrap()
+                .sandbox_only
+        );
+        assert_eq!(lookup(OsStr::new("HOME"), SNAP_CATALOG), None);
+    }
+
+    #[test]
+    fn catalog_has_no_duplicates() {
+        for catalog in &[SNAP_CATALOG, APPIMAGE_CATALOG, FLATPAK_CATALOG] {
+            let catalog: Vec<&KnownVariable> = catalog.iter().chain(COMMON_CATALOG).collect();
+            for (i, known) in catalog.iter().enumerate() {
+                assert!(
+                    catalog[i + 1..]
+                        .iter()
+                        .all(|other| other.name != known.name),
+                    "{} is in the catalog more than once",
+                    known.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn restores_known_snap_variable_set_by_launcher() {
+        let var = Variable::new(
+            None,
+            None,
+            Some(OsString::from("/usr/lib/gdk/loaders.cache")),
+        )
+        .with_known(lookup(OsStr::new("GDK_PIXBUF_MODULE_FILE"), SNAP_CATALOG));
+        assert_eq!(var.get_child_value(), None);
+        assert_eq!(
+            var.get_reason(&mock_locations()),
+            "set by snap launcher, restored"
+        );
+        let var = Variable::new(
+            Some(OsString::from("/usr/lib/qt")),
+            Some(OsString::from("/usr/lib/qt")),
+            Some(OsString::from("/opt/qt:/usr/lib/qt")),
+        )
+        .with_known(lookup(OsStr::new("QT_PLUGIN_PATH"), SNAP_CATALOG));
+        assert_eq!(
+            var.get_child_value(),
+            Some(OsString::from("/opt/qt:/usr/lib/qt"))
+        );
+        assert_eq!(
+            var.get_reason(&mock_locations()),
+            "changed after snap, kept"
+        );
+    }
+
+    #[test]
+    fn merges_preloads_separated_by_spaces_or_colons() {
+        let var = Variable::new(
+            Some(OsString::from("/usr/lib/libuser.so")),
+            Some(OsString::from(
+                "/usr/lib/libuser.so /snap/foo/12/lib/libpreload.so",
+            )),
+            Some(OsString::from(
+                "/opt/libtool.so:/usr/lib/libuser.so /snap/foo/12/lib/libpreload.so",
+            )),
+        )
+        .with_known(lookup(OsStr::new("LD_PRELOAD"), SNAP_CATALOG));
+        assert_eq!(
+            var.get_child_value(),
+            Some(OsString::from("/opt/libtool.so /usr/lib/libuser.so"))
+        );
+    }
+
+    #[test]
+    fn restores_list_changed_only_in_snap() {
+        assert_eq!(
+            list(
+                Some("/usr/bin:/bin"),
+                Some("/snap/a/bin:/usr/bin:/bin"),
+                Some("/snap/a/bin:/usr/bin:/bin")
+            )
+            .get_child_value(),
+            Some(OsString::from("/usr/bin:/bin"))
+        );
+    }
+
+    #[test]
+    fn removes_snap_entries_from_list_changed_in_myself() {
+        assert_eq!(
+            list(
+                Some("/usr/bin:/bin"),
+                Some("/snap/a/bin:/usr/bin:/bin"),
+                Some("/opt/tool/bin:/snap/a/bin:/usr/bin:/bin"),
+            )
+            .get_child_value(),
+            Some(OsString::from("/opt/tool/bin:/usr/bin:/bin"))
+        );
+    }
+
+    #[test]
+    fn keeps_entries_removed_in_myself_removed() {
+        assert_eq!(
+            list(
+                Some("/usr/bin:/bin"),
+                Some("/snap/a/bin:/usr/bin:/bin"),
+                Some("/snap/a/bin:/bin:/opt/tool/bin"),
+            )
+            .get_child_value(),
+            Some(OsString::from("/bin:/opt/tool/bin"))
+        );
+    }
+
+    #[test]
+    fn keeps_external_order_of_list_reordered_by_snap() {
+        assert_eq!(
+            list(
+                Some("/usr/bin:/bin"),
+                Some("/snap/a/bin:/bin:/usr/bin"),
+                Some("/opt/tool/bin:/snap/a/bin:/bin:/usr/bin"),
+            )
+            .get_child_value(),
+            Some(OsString::from("/opt/tool/bin:/usr/bin:/bin"))
+        );
+    }
+
+    #[test]
+    fn restores_entries_removed_by_snap() {
+        assert_eq!(
+            list(
+                Some("/usr/local/bin:/usr/bin:/bin"),
+                Some("/app/bin:/usr/bin"),
+                Some("/home/a/bin:/app/bin:/usr/bin:/opt/bin"),
+            )
+            .get_child_value(),
+            Some(OsString::from(
+                "/home/a/bin:/usr/local/bin:/usr/bin:/opt/bin:/bin"
+            ))
+        );
+        assert_eq!(
+            list(
+                Some("/usr/local/bin:/usr/bin"),
+                None,
+                Some("/home/a/bin:/app/bin:/usr/bin")
+            )
+            .get_child_value(),
+            Some(OsString::from(
+                "/home/a/bin:/app/bin:/usr/local/bin:/usr/bin"
+            ))
+        );
+    }
+
+    #[test]
+    fn keeps_only_own_entries_of_list_set_by_snap() {
+        assert_eq!(
+            list(None, Some("/snap/a/lib"), Some("/snap/a/lib:/opt/lib")).get_child_value(),
+            Some(OsString::from("/opt/lib"))
+        );
+        assert_eq!(
+            list(None, Some("/snap/a/lib"), Some("/snap/a/lib")).get_child_value(),
+            None
+        );
+    }
+
+    #[test]
+    fn leaves_list_cleared_in_myself() {
+        assert_eq!(
+            list(Some("/usr/bin"), Some("/snap/a/bin:/usr/bin"), None).get_child_value(),
+            None
+        );
+    }
+
     #[test]
     fn clears_variable_set_in_snap() {
         assert_eq!(
@@ -156,6 +1097,178 @@ mod tests {
         );
     }
 
+    fn mock_locations() -> Locations {
+        let mut env = std::collections::HashMap::new();
+        env.insert(OsString::from("SNAP"), OsString::from("/snap/foo/12"));
+        env.insert(
+            OsString::from("SNAP_DATA"),
+            OsString::from("/var/snap/foo/12"),
+        );
+        Locations::from_snap_env(&env)
+    }
+
+    #[test]
+    fn strips_snap_entries_from_untouched_list() {
+        assert_eq!(
+            list(
+                Some("/snap/foo/11/bin:/usr/bin"),
+                Some("/snap/foo/11/bin:/usr/bin"),
+                Some("/snap/foo/11/bin:/usr/bin"),
+            )
+            .get_clean_value(&mock_locations()),
+            Some(OsString::from("/usr/bin"))
+        );
+    }
+
+    #[test]
+    fn strips_snap_entries_added_by_wrapper() {
+        assert_eq!(
+            list(
+                Some("/usr/bin"),
+                Some("/usr/bin"),
+                Some("/var/snap/foo/common/bin:/usr/bin"),
+            )
+            .get_clean_value(&mock_locations()),
+            Some(OsString::from("/usr/bin"))
+        );
+        assert_eq!(
+            list(None, None, Some("/snap/foo/current/lib")).get_clean_value(&mock_locations()),
+            None
+        );
+    }
+
+    #[test]
+    fn falls_back_when_list_only_points_into_snap() {
+        assert_eq!(
+            list(
+                Some("/usr/bin:/bin"),
+                Some("/usr/bin:/bin"),
+                Some("/snap/foo/11/bin:/snap/foo/11/usr/bin"),
+            )
+            .get_clean_value(&mock_locations()),
+            Some(OsString::from("/usr/bin:/bin"))
+        );
+        assert_eq!(
+            list(
+                Some("/snap/foo/11/lib"),
+                Some("/snap/foo/11/lib"),
+                Some("/snap/foo/11/lib:/snap/foo/11/usr/lib"),
+            )
+            .get_clean_value(&mock_locations()),
+            None
+        );
+    }
+
+    #[test]
+    fn restores_value_pointing_into_snap() {
+        assert_eq!(
+            Variable::new(
+                Some(OsString::from("/usr/lib/gtk")),
+                Some(OsString::from("/usr/lib/gtk")),
+                Some(OsString::from("/snap/foo/12/lib/gtk")),
+            )
+            .get_clean_value(&mock_locations()),
+            Some(OsString::from("/usr/lib/gtk"))
+        );
+    }
+
+    #[test]
+    fn clears_value_only_pointing_into_snap() {
+        assert_eq!(
+            Variable::new(
+                Some(OsString::from("/snap/foo/11/lib/gtk")),
+                Some(OsString::from("/snap/foo/11/lib/gtk")),
+                Some(OsString::from("/snap/foo/12/lib/gtk")),
+            )
+            .get_clean_value(&mock_locations()),
+            None
+        );
+    }
+
+    #[test]
+    fn leaves_values_outside_snap() {
+        assert_eq!(
+            Variable::new(None, None, Some(OsString::from("/usr/share")))
+                .get_clean_value(&mock_locations()),
+            Some(OsString::from("/usr/share"))
+        );
+    }
+
+    #[test]
+    fn follows_policy() {
+        let mut var = Variable::new(
+            Some(OsString::from("foo")),
+            Some(OsString::from("/snap/foo/12")),
+            Some(OsString::from("/snap/foo/12")),
+        );
+        let locations = mock_locations();
+        assert_eq!(
+            var.get_required_change(&locations),
+            Some(Some(OsString::from("foo")))
+        );
+        var.set_policy(Policy::Keep);
+        assert_eq!(var.get_required_change(&locations), None);
+        var.set_policy(Policy::Unset);
+        assert_eq!(var.get_required_change(&locations), Some(None));
+        var.set_policy(Policy::Restore);
+        assert_eq!(
+            var.get_required_change(&locations),
+            Some(Some(OsString::from("foo")))
+        );
+    }
+
+    #[test]
+    fn explains_reason() {
+        let locations = mock_locations();
+        let var = |external: Option<&str>, snap: Option<&str>, myself: Option<&str>| {
+            Variable::new(
+                external.map(OsString::from),
+                snap.map(OsString::from),
+                myself.map(OsString::from),
+            )
+        };
+        assert_eq!(
+            var(Some("a"), Some("a"), Some("a")).get_reason(&locations),
+            "unchanged"
+        );
+        assert_eq!(
+            var(None, Some("a"), Some("a")).get_reason(&locations),
+            "set by snap, cleared"
+        );
+        assert_eq!(
+            var(Some("a"), None, None).get_reason(&locations),
+            "cleared by snap, restored"
+        );
+        assert_eq!(
+            var(Some("a"), Some("b"), Some("b")).get_reason(&locations),
+            "changed by snap, restored"
+        );
+        assert_eq!(
+            var(Some("a"), Some("b"), Some("c")).get_reason(&locations),
+            "changed after snap, kept"
+        );
+        assert_eq!(
+            var(Some("a"), Some("b"), None).get_reason(&locations),
+            "cleared after snap, kept"
+        );
+        assert_eq!(
+            var(Some("a"), Some("a"), Some("/snap/foo/12/a")).get_reason(&locations),
+            "points into snap, restored"
+        );
+        assert_eq!(
+            list(
+                Some("/bin"),
+                Some("/snap/a:/bin"),
+                Some("/opt:/snap/a:/bin")
+            )
+            .get_reason(&locations),
+            "changed after snap, snap entries removed"
+        );
+        let mut keep = var(Some("a"), Some("b"), Some("b"));
+        keep.set_policy(Policy::Keep);
+        assert_eq!(keep.get_reason(&locations), "kept by rule");
+    }
+
     #[test]
     fn detects_no_required_change_to_value() {
         assert_eq!(